@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    TarGz,
+    TarXz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    /// Guesses the format from the file name of `path`.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+            Some(ArchiveFormat::TarXz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(ArchiveFormat::TarZst)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ArchiveEntryKind {
+    File { size: u64 },
+    Directory,
+    Symlink(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Normalized path of the entry inside the archive.
+    pub path: PathBuf,
+    pub kind: ArchiveEntryKind,
+    pub mode: Option<u32>,
+}
+
+/// Permission bits kept from archive entries. Setuid, setgid and sticky bits
+/// from an untrusted archive are never installed.
+const MODE_MASK: u32 = 0o777;
+
+/// Lists every entry of the archive at `archive`.
+///
+/// Entries whose paths are absolute or escape the archive root, and entries
+/// other than files, directories and symbolic links, such as hard links or
+/// devices, are rejected with `io::ErrorKind::InvalidData`.
+pub fn list(archive: &Path) -> io::Result<Vec<ArchiveEntry>> {
+    let format = detect(archive)?;
+    let mut entries = Vec::with_capacity(128);
+    if format == ArchiveFormat::Zip {
+        let mut zip =
+            zip::ZipArchive::new(BufReader::new(File::open(archive)?)).map_err(io::Error::other)?;
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i).map_err(io::Error::other)?;
+            let path = normalize(Path::new(file.name()))?;
+            let kind = if file.is_dir() {
+                ArchiveEntryKind::Directory
+            } else if file.is_symlink() {
+                let mut target = String::new();
+                file.read_to_string(&mut target)?;
+                ArchiveEntryKind::Symlink(PathBuf::from(target))
+            } else {
+                ArchiveEntryKind::File { size: file.size() }
+            };
+            entries.push(ArchiveEntry {
+                path,
+                kind,
+                mode: file.unix_mode().map(|m| m & MODE_MASK),
+            });
+        }
+    } else {
+        let mut tar = open_tar(archive, format)?;
+        for entry in tar.entries()? {
+            let entry = entry?;
+            let header = entry.header();
+            let entry_type = header.entry_type();
+            // Global extended headers only carry metadata for the archive.
+            if entry_type.is_pax_global_extensions() {
+                continue;
+            }
+            let path = normalize(&entry.path()?)?;
+            let kind = match entry_type {
+                tar::EntryType::Directory => ArchiveEntryKind::Directory,
+                tar::EntryType::Symlink => match entry.link_name()? {
+                    Some(target) => ArchiveEntryKind::Symlink(target.into_owned()),
+                    None => return Err(unsupported_entry(&path, "symbolic link without target")),
+                },
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    ArchiveEntryKind::File { size: entry.size() }
+                }
+                tar::EntryType::Link => return Err(unsupported_entry(&path, "hard link")),
+                other => return Err(unsupported_entry(&path, &format!("{:?}", other))),
+            };
+            entries.push(ArchiveEntry {
+                path,
+                kind,
+                mode: header.mode().ok().map(|m| m & MODE_MASK),
+            });
+        }
+    }
+    Ok(entries)
+}
+
+/// Reported by `extract` around each entry, by index into its `entries`.
+#[derive(Debug, Clone, Copy)]
+pub enum ExtractEvent<'a> {
+    Started(usize),
+    /// The next chunk of the entry's contents, right after it was written.
+    Data {
+        index: usize,
+        data: &'a [u8],
+    },
+    Finished {
+        index: usize,
+        bytes: u64,
+    },
+}
+
+/// Streams the files named by `entries` out of `archive` into their paths on
+/// disk, in a single pass over the archive.
+///
+/// On failure the error carries how many leading entries were already written.
+pub async fn extract(
+    archive: PathBuf,
+    entries: Vec<(PathBuf, PathBuf)>,
+    mut on_event: impl FnMut(ExtractEvent<'_>) + Send + 'static,
+) -> Result<(), (usize, io::Error)> {
+    tokio::task::spawn_blocking(move || extract_blocking(&archive, &entries, &mut on_event))
+        .await
+        .map_err(|e| (0, io::Error::other(e)))?
+}
+
+fn extract_blocking(
+    archive: &Path,
+    entries: &[(PathBuf, PathBuf)],
+    on_event: &mut impl FnMut(ExtractEvent<'_>),
+) -> Result<(), (usize, io::Error)> {
+    let format = detect(archive).map_err(|e| (0, e))?;
+    if format == ArchiveFormat::Zip {
+        let mut zip = File::open(archive)
+            .and_then(|f| zip::ZipArchive::new(BufReader::new(f)).map_err(io::Error::other))
+            .map_err(|e| (0, e))?;
+        // Entries are stored under their raw names, which may differ from the
+        // normalized ones, for example `./bin/app` for `bin/app`.
+        let mut indices = HashMap::with_capacity(zip.len());
+        for i in 0..zip.len() {
+            if let Some(name) = zip.name_for_index(i)
+                && let Ok(path) = normalize(Path::new(name))
+            {
+                indices.entry(path).or_insert(i);
+            }
+        }
+        for (i, (entry, to)) in entries.iter().enumerate() {
+            let Some(&index) = indices.get(entry) else {
+                let error = io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} not found in archive", entry.display()),
+                );
+                return Err((i, error));
+            };
+            let mut file = zip.by_index(index).map_err(|e| (i, io::Error::other(e)))?;
+            let mode = file.unix_mode();
+            on_event(ExtractEvent::Started(i));
+            let on_data = |data: &[u8]| on_event(ExtractEvent::Data { index: i, data });
+            let bytes = write_entry(&mut file, to, mode, on_data).map_err(|e| (i, e))?;
+            on_event(ExtractEvent::Finished { index: i, bytes });
+        }
+        return Ok(());
+    }
+    // Tar archives can only be read front to back, so entries are written as
+    // they stream past and a second pass is only needed for out-of-order requests.
+    let mut written = 0;
+    while written < entries.len() {
+        let start = written;
+        let mut tar = open_tar(archive, format).map_err(|e| (written, e))?;
+        for entry in tar.entries().map_err(|e| (written, e))? {
+            let mut entry = entry.map_err(|e| (written, e))?;
+            let path = entry
+                .path()
+                .map_err(|e| (written, e))
+                .and_then(|p| normalize(&p).map_err(|e| (written, e)))?;
+            if path != entries[written].0 {
+                continue;
+            }
+            let mode = entry.header().mode().ok();
+            on_event(ExtractEvent::Started(written));
+            let on_data = |data: &[u8]| {
+                on_event(ExtractEvent::Data {
+                    index: written,
+                    data,
+                })
+            };
+            let bytes = write_entry(&mut entry, &entries[written].1, mode, on_data)
+                .map_err(|e| (written, e))?;
+            on_event(ExtractEvent::Finished {
+                index: written,
+                bytes,
+            });
+            written += 1;
+            if written == entries.len() {
+                break;
+            }
+        }
+        if written == start {
+            let error = io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found in archive", entries[written].0.display()),
+            );
+            return Err((written, error));
+        }
+    }
+    Ok(())
+}
+
+fn write_entry(
+    reader: &mut impl Read,
+    to: &Path,
+    mode: Option<u32>,
+    on_data: impl FnMut(&[u8]),
+) -> io::Result<u64> {
+    let mut file = File::create(to)?;
+    let bytes = crate::file_system::copy_stream(reader, &mut file, on_data)?;
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode & MODE_MASK))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    Ok(bytes)
+}
+
+fn unsupported_entry(path: &Path, kind: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unsupported archive entry {}: {}", path.display(), kind),
+    )
+}
+
+fn detect(archive: &Path) -> io::Result<ArchiveFormat> {
+    ArchiveFormat::from_path(archive).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("unknown archive format: {}", archive.display()),
+        )
+    })
+}
+
+fn open_tar(archive: &Path, format: ArchiveFormat) -> io::Result<tar::Archive<Box<dyn Read>>> {
+    let file = BufReader::new(File::open(archive)?);
+    let reader: Box<dyn Read> = match format {
+        ArchiveFormat::Tar => Box::new(file),
+        ArchiveFormat::TarGz => Box::new(flate2::read::GzDecoder::new(file)),
+        ArchiveFormat::TarXz => Box::new(xz2::read::XzDecoder::new(file)),
+        ArchiveFormat::TarZst => Box::new(zstd::stream::read::Decoder::with_buffer(file)?),
+        ArchiveFormat::Zip => unreachable!(),
+    };
+    Ok(tar::Archive::new(reader))
+}
+
+/// Strips `.` components and rejects paths that are absolute or contain `..`.
+fn normalize(path: &Path) -> io::Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsafe archive entry: {}", path.display()),
+                ));
+            }
+        }
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "bundle-deploy-archive-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn tar_header(path: &str, entry_type: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_path(path).unwrap();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_size(size);
+        header.set_cksum();
+        header
+    }
+
+    #[test]
+    fn tar_modes_drop_special_bits() {
+        let dir = temp_dir("modes");
+        let archive = dir.join("a.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        let header = tar_header("bin/", tar::EntryType::Directory, 0o1755, 0);
+        builder.append(&header, io::empty()).unwrap();
+        let header = tar_header("bin/app", tar::EntryType::Regular, 0o4755, 2);
+        builder.append(&header, &b"hi"[..]).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let entries = list(&archive).unwrap();
+        assert_eq!(entries[0].mode, Some(0o755));
+        assert_eq!(entries[1].mode, Some(0o755));
+
+        let to = dir.join("app");
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(extract(
+            archive,
+            vec![(PathBuf::from("bin/app"), to.clone())],
+            |_| {},
+        ))
+        .unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"hi");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&to).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o755);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tar_hard_links_are_rejected() {
+        let dir = temp_dir("hard-link");
+        let archive = dir.join("a.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        let header = tar_header("a", tar::EntryType::Regular, 0o644, 2);
+        builder.append(&header, &b"hi"[..]).unwrap();
+        let mut header = tar_header("b", tar::EntryType::Link, 0o644, 0);
+        header.set_link_name("a").unwrap();
+        header.set_cksum();
+        builder.append(&header, io::empty()).unwrap();
+        builder.finish().unwrap();
+        drop(builder);
+
+        let error = list(&archive).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn zip_entries_are_found_by_normalized_name() {
+        let dir = temp_dir("zip");
+        let archive = dir.join("a.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Stored)
+            .unix_permissions(0o2755);
+        zip.start_file("./bin/app", options).unwrap();
+        zip.write_all(b"hi").unwrap();
+        zip.finish().unwrap();
+
+        let raw = zip::ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+        assert_eq!(raw.name_for_index(0), Some("./bin/app"));
+        let entries = list(&archive).unwrap();
+        assert_eq!(entries[0].path, PathBuf::from("bin/app"));
+        assert_eq!(entries[0].mode, Some(0o755));
+
+        let to = dir.join("app");
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(extract(
+            archive,
+            vec![(PathBuf::from("bin/app"), to.clone())],
+            |_| {},
+        ))
+        .unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"hi");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn extract_reports_the_data_written() {
+        let dir = temp_dir("data");
+        let archive = dir.join("a.tar");
+        let mut builder = tar::Builder::new(File::create(&archive).unwrap());
+        let contents: [&[u8]; 2] = [b"first", b"second entry"];
+        for (name, data) in ["a", "b"].iter().zip(contents) {
+            let header = tar_header(name, tar::EntryType::Regular, 0o644, data.len() as u64);
+            builder.append(&header, data).unwrap();
+        }
+        builder.finish().unwrap();
+        drop(builder);
+
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(vec![Vec::new(), Vec::new()]));
+        let events = seen.clone();
+        let entries = vec![
+            (PathBuf::from("a"), dir.join("a")),
+            (PathBuf::from("b"), dir.join("b")),
+        ];
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(extract(archive, entries, move |event| {
+            if let ExtractEvent::Data { index, data } = event {
+                events.lock().unwrap()[index].extend_from_slice(data);
+            }
+        }))
+        .unwrap();
+        let seen = seen.lock().unwrap();
+        for (i, name) in ["a", "b"].iter().enumerate() {
+            assert_eq!(seen[i], contents[i]);
+            assert_eq!(std::fs::read(dir.join(name)).unwrap(), contents[i]);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
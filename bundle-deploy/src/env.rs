@@ -1,17 +1,481 @@
 use directories::BaseDirs;
 use std::env;
-use std::path::PathBuf;
-
-pub fn get_software_install_paths() -> (PathBuf, PathBuf) {
-    let user_path = BaseDirs::new().map_or("/".into(), |dirs| dirs.data_local_dir().into());
-    let global_path = if cfg!(target_os = "windows") {
-        env::var("ProgramFiles").map_or("/".into(), |p| p.into())
-    } else if cfg!(target_os = "linux") {
-        "/usr/bin".into()
-    } else if cfg!(target_os = "macos") {
-        "/Applications".into()
-    } else {
-        "/".into()
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Where software is installed, for every user and for the current one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstallLocations {
+    /// The current user's home directory, `/` when it is unknown.
+    pub home: PathBuf,
+    /// Root under which applications for every user get their own directory,
+    /// `/opt` on Linux.
+    pub global_root: PathBuf,
+    /// Where launchers for every user are linked, `/usr/local/bin` on Linux.
+    pub global_bin: PathBuf,
+    /// Root of the current user's applications, `$XDG_DATA_HOME` or
+    /// `~/.local/share` on Linux.
+    pub user_root: PathBuf,
+    /// `$XDG_BIN_HOME` or `~/.local/bin` on Linux.
+    pub user_bin: PathBuf,
+    /// Where the current user's desktop entries go,
+    /// `~/.local/share/applications` on Linux.
+    pub user_applications: PathBuf,
+}
+
+impl InstallLocations {
+    /// The locations for the environment of this process.
+    pub fn current() -> Self {
+        Self::from_env(|name| env::var_os(name))
+    }
+
+    /// The locations for an environment in which `var` looks up variables,
+    /// so that they can be computed for an environment other than the real
+    /// one.
+    ///
+    /// Following the XDG base directory specification, variables holding
+    /// relative paths are ignored.
+    pub fn from_env(var: impl Fn(&str) -> Option<OsString>) -> Self {
+        let path = |name: &str| var(name).map(PathBuf::from).filter(|p| p.is_absolute());
+        let home = path("HOME")
+            .or_else(|| path("USERPROFILE"))
+            .unwrap_or_else(|| PathBuf::from("/"));
+        if cfg!(target_os = "windows") {
+            let local = path("LOCALAPPDATA").unwrap_or_else(|| home.join("AppData\\Local"));
+            let roaming = path("APPDATA").unwrap_or_else(|| home.join("AppData\\Roaming"));
+            let global_root =
+                path("ProgramFiles").unwrap_or_else(|| PathBuf::from("C:\\Program Files"));
+            let user_root = local.join("Programs");
+            Self {
+                home,
+                global_bin: global_root.join("bin"),
+                global_root,
+                user_bin: user_root.join("bin"),
+                user_root,
+                user_applications: roaming.join("Microsoft\\Windows\\Start Menu\\Programs"),
+            }
+        } else if cfg!(target_os = "macos") {
+            Self {
+                global_root: PathBuf::from("/Applications"),
+                global_bin: PathBuf::from("/usr/local/bin"),
+                user_root: home.join("Applications"),
+                user_bin: home.join(".local/bin"),
+                user_applications: home.join("Applications"),
+                home,
+            }
+        } else {
+            let data_home = path("XDG_DATA_HOME").unwrap_or_else(|| home.join(".local/share"));
+            Self {
+                global_root: PathBuf::from("/opt"),
+                global_bin: PathBuf::from("/usr/local/bin"),
+                user_bin: path("XDG_BIN_HOME").unwrap_or_else(|| home.join(".local/bin")),
+                user_applications: data_home.join("applications"),
+                user_root: data_home,
+                home,
+            }
+        }
+    }
+}
+
+/// Whether the process runs as root. Other platforms are not checked and
+/// always report `true`; writes there fail on their own without the rights.
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    // SAFETY: `geteuid` has no preconditions and cannot fail.
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    true
+}
+
+const BLOCK_BEGIN: &str = "# >>> veridian-manager >>>";
+const BLOCK_END: &str = "# <<< veridian-manager <<<";
+
+#[derive(Debug, Clone)]
+pub enum EnvFileFormat {
+    /// A POSIX shell profile such as `~/.profile`.
+    Shell,
+    /// A systemd `environment.d` drop-in file.
+    EnvironmentD,
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvTarget {
+    pub path: PathBuf,
+    pub format: EnvFileFormat,
+}
+
+impl EnvTarget {
+    pub fn new(path: PathBuf, format: EnvFileFormat) -> Self {
+        Self { path, format }
+    }
+
+    pub fn shell_profile() -> Self {
+        let home = BaseDirs::new().map_or("/".into(), |dirs| dirs.home_dir().to_path_buf());
+        Self::new(home.join(".profile"), EnvFileFormat::Shell)
+    }
+
+    pub fn environment_d() -> Self {
+        let config = BaseDirs::new().map_or("/".into(), |dirs| dirs.config_dir().to_path_buf());
+        Self::new(
+            config
+                .join("environment.d")
+                .join("60-veridian-manager.conf"),
+            EnvFileFormat::EnvironmentD,
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum EnvChange {
+    PrependPath(PathBuf),
+    Set { name: String, value: String },
+    Append { name: String, value: String },
+}
+
+impl EnvChange {
+    /// Renders the change as the single line written into `format`. Values
+    /// with control characters such as newlines are refused in both formats,
+    /// since neither can hold them on one line, and the characters either
+    /// format would expand are escaped.
+    pub fn render(&self, format: &EnvFileFormat) -> io::Result<String> {
+        match self {
+            EnvChange::PrependPath(path) => {
+                let path = path.to_string_lossy();
+                check_value(&path)?;
+                Ok(match format {
+                    EnvFileFormat::Shell => {
+                        format!("export PATH=\"{}:$PATH\"", escape_shell(&path))
+                    }
+                    EnvFileFormat::EnvironmentD => {
+                        format!("PATH={}:${{PATH}}", escape_environment_d(&path))
+                    }
+                })
+            }
+            EnvChange::Set { name, value } => {
+                check_name(name)?;
+                check_value(value)?;
+                Ok(match format {
+                    EnvFileFormat::Shell => format!("export {}=\"{}\"", name, escape_shell(value)),
+                    EnvFileFormat::EnvironmentD => {
+                        format!("{}={}", name, escape_environment_d(value))
+                    }
+                })
+            }
+            EnvChange::Append { name, value } => {
+                check_name(name)?;
+                check_value(value)?;
+                Ok(match format {
+                    EnvFileFormat::Shell => format!(
+                        "export {0}=\"${{{0}:+${0}:}}{1}\"",
+                        name,
+                        escape_shell(value)
+                    ),
+                    EnvFileFormat::EnvironmentD => {
+                        format!(
+                            "{0}=${{{0}:+${{{0}}}:}}{1}",
+                            name,
+                            escape_environment_d(value)
+                        )
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// What [`apply`] wrote into an environment file, which is what [`revert`]
+/// needs to undo it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Applied {
+    /// The exact line written.
+    pub line: String,
+    /// Whether the file did not exist and was created.
+    pub created: bool,
+}
+
+/// Writes `change` into the managed block of `target`. The directory of the
+/// file must already exist.
+pub async fn apply(target: &EnvTarget, change: &EnvChange) -> io::Result<Applied> {
+    let line = change.render(&target.format)?;
+    let existing = read_lines(&target.path).await?;
+    let created = existing.is_none();
+    let mut lines = existing.unwrap_or_default();
+    match find_block(&lines) {
+        Some((_, end)) => lines.insert(end, line.clone()),
+        None => {
+            if lines.last().is_some_and(|l| !l.is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(BLOCK_BEGIN.to_string());
+            lines.push(line.clone());
+            lines.push(BLOCK_END.to_string());
+        }
+    }
+    write_lines(&target.path, &lines).await?;
+    Ok(Applied { line, created })
+}
+
+/// Removes one occurrence of `line` from the managed block in `path`.
+///
+/// Lines outside the block are never touched. The block itself is removed
+/// once it is empty, and so is the file once nothing else is left in it, but
+/// only if `created` says that the [`apply`] which wrote `line` created it.
+pub async fn revert(path: &Path, line: &str, created: bool) -> io::Result<()> {
+    let Some(mut lines) = read_lines(path).await? else {
+        return Ok(());
+    };
+    let Some((begin, end)) = find_block(&lines) else {
+        return Ok(());
     };
-    (user_path, global_path)
+    let Some(index) = (begin + 1..end).rev().find(|&i| lines[i] == line) else {
+        return Ok(());
+    };
+    lines.remove(index);
+    if end - begin == 2 {
+        lines.drain(begin..begin + 2);
+        if begin > 0 && begin == lines.len() && lines[begin - 1].is_empty() {
+            lines.pop();
+        }
+        if lines.is_empty() && created {
+            return tokio::fs::remove_file(path).await;
+        }
+    }
+    write_lines(path, &lines).await
+}
+
+fn find_block(lines: &[String]) -> Option<(usize, usize)> {
+    let begin = lines.iter().position(|l| l == BLOCK_BEGIN)?;
+    let end = lines[begin..].iter().position(|l| l == BLOCK_END)? + begin;
+    Some((begin, end))
+}
+
+/// The lines of `path`, `None` if it does not exist.
+async fn read_lines(path: &Path) -> io::Result<Option<Vec<String>>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(contents) => Ok(Some(contents.lines().map(str::to_string).collect())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the contents of `path` by writing a temporary file next to it and
+/// renaming it into place, so that a shell reading `path` never sees it half
+/// written. The permissions of an existing file are kept.
+async fn write_lines(path: &Path, lines: &[String]) -> io::Result<()> {
+    let mut contents = lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    let mut temp_name = path.file_name().map(OsString::from).unwrap_or_default();
+    temp_name.push(format!(".veridian-tmp-{}", std::process::id()));
+    let temp = path.with_file_name(temp_name);
+    let res = async {
+        tokio::fs::write(&temp, contents).await?;
+        if let Ok(metadata) = tokio::fs::metadata(path).await {
+            tokio::fs::set_permissions(&temp, metadata.permissions()).await?;
+        }
+        tokio::fs::rename(&temp, path).await
+    }
+    .await;
+    if res.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    res
+}
+
+fn check_name(name: &str) -> io::Result<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid environment variable name: {}", name),
+        ))
+    }
+}
+
+fn check_value(value: &str) -> io::Result<()> {
+    if value.chars().any(char::is_control) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "environment value contains a control character: {:?}",
+                value
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Escapes `$` and `\`, which systemd expands in `environment.d` values.
+fn escape_environment_d(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '\\' | '$') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn escape_shell(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locations(vars: &[(&str, &str)]) -> InstallLocations {
+        InstallLocations::from_env(|name| {
+            vars.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| OsString::from(v))
+        })
+    }
+
+    #[test]
+    fn control_characters_are_refused() {
+        let changes = [
+            EnvChange::PrependPath(PathBuf::from("/opt/a\nb")),
+            EnvChange::Set {
+                name: "A".to_string(),
+                value: "x\ny".to_string(),
+            },
+            EnvChange::Append {
+                name: "A".to_string(),
+                value: "x\ry".to_string(),
+            },
+            EnvChange::Set {
+                name: "A".to_string(),
+                value: "x\u{7f}".to_string(),
+            },
+        ];
+        for change in &changes {
+            for format in [EnvFileFormat::Shell, EnvFileFormat::EnvironmentD] {
+                let err = change.render(&format).unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            }
+        }
+        let change = EnvChange::Set {
+            name: "A".to_string(),
+            value: "a \"b\" $c".to_string(),
+        };
+        assert_eq!(
+            change.render(&EnvFileFormat::Shell).unwrap(),
+            "export A=\"a \\\"b\\\" \\$c\""
+        );
+        let change = EnvChange::Append {
+            name: "A".to_string(),
+            value: "$HOME\\bin".to_string(),
+        };
+        assert_eq!(
+            change.render(&EnvFileFormat::EnvironmentD).unwrap(),
+            "A=${A:+${A}:}\\$HOME\\\\bin"
+        );
+        let change = EnvChange::PrependPath(PathBuf::from("/opt/$a"));
+        assert_eq!(
+            change.render(&EnvFileFormat::EnvironmentD).unwrap(),
+            "PATH=/opt/\\$a:${PATH}"
+        );
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn xdg_variables_override_home() {
+        let l = locations(&[
+            ("HOME", "/home/u"),
+            ("XDG_DATA_HOME", "/data"),
+            ("XDG_BIN_HOME", "/bin-home"),
+        ]);
+        assert_eq!(l.home, PathBuf::from("/home/u"));
+        assert_eq!(l.user_root, PathBuf::from("/data"));
+        assert_eq!(l.user_bin, PathBuf::from("/bin-home"));
+        assert_eq!(l.user_applications, PathBuf::from("/data/applications"));
+        assert_eq!(l.global_root, PathBuf::from("/opt"));
+        assert_eq!(l.global_bin, PathBuf::from("/usr/local/bin"));
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn relative_xdg_variables_are_ignored() {
+        let l = locations(&[
+            ("HOME", "/home/u"),
+            ("XDG_DATA_HOME", "data"),
+            ("XDG_BIN_HOME", "./bin"),
+        ]);
+        assert_eq!(l.user_root, PathBuf::from("/home/u/.local/share"));
+        assert_eq!(l.user_bin, PathBuf::from("/home/u/.local/bin"));
+        assert_eq!(
+            l.user_applications,
+            PathBuf::from("/home/u/.local/share/applications")
+        );
+    }
+
+    #[test]
+    #[cfg(all(unix, not(target_os = "macos")))]
+    fn home_falls_back_to_the_root_directory() {
+        let l = locations(&[("HOME", "relative")]);
+        assert_eq!(l.home, PathBuf::from("/"));
+        assert_eq!(l.user_root, PathBuf::from("/.local/share"));
+        assert_eq!(l.user_bin, PathBuf::from("/.local/bin"));
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bundle-deploy-env-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn only_created_files_are_removed_on_revert() {
+        let dir = temp_dir("created");
+        let change = EnvChange::PrependPath(PathBuf::from("/opt/demo/bin"));
+        let created = EnvTarget::new(dir.join("created.sh"), EnvFileFormat::Shell);
+        let existing = EnvTarget::new(dir.join("existing.sh"), EnvFileFormat::Shell);
+        std::fs::write(&existing.path, "").unwrap();
+
+        let applied = block_on(apply(&created, &change)).unwrap();
+        assert!(applied.created);
+        let again = block_on(apply(&created, &change)).unwrap();
+        assert!(!again.created);
+        block_on(revert(&created.path, &again.line, again.created)).unwrap();
+        block_on(revert(&created.path, &applied.line, applied.created)).unwrap();
+        assert!(!created.path.exists());
+
+        let applied = block_on(apply(&existing, &change)).unwrap();
+        assert!(!applied.created);
+        block_on(revert(&existing.path, &applied.line, applied.created)).unwrap();
+        assert_eq!(std::fs::read_to_string(&existing.path).unwrap(), "");
+
+        // Nothing but the file itself is left behind by the writes.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
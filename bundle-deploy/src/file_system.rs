@@ -1,17 +1,33 @@
 use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 pub use tokio::fs::*;
 
+#[derive(Debug)]
+pub enum PathError {
+    EmptyFileName,
+    IllegalChars(OsString),
+    OutOfRoot,
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+impl std::error::Error for PathError {}
+
 #[derive(Debug, Clone)]
 pub struct FileName(OsString);
 
 impl FileName {
-    pub fn new(s: OsString) -> Result<Self, ()> {
+    pub fn new(s: OsString) -> Result<Self, PathError> {
         if s.is_empty() {
-            return Err(());
+            return Err(PathError::EmptyFileName);
         }
         if Self::contains_illegal_chars(&s) {
-            return Err(());
+            return Err(PathError::IllegalChars(s));
         }
         Ok(Self(s))
     }
@@ -47,9 +63,9 @@ impl FileName {
     }
 }
 
-impl Into<OsString> for FileName {
-    fn into(self) -> OsString {
-        self.0
+impl From<FileName> for OsString {
+    fn from(file_name: FileName) -> Self {
+        file_name.0
     }
 }
 
@@ -69,7 +85,7 @@ impl From<FileName> for RelativePathComponent {
 pub struct RelativePath(Vec<RelativePathComponent>);
 
 impl RelativePath {
-    pub fn new(path: impl IntoIterator<Item = impl Into<OsString>>) -> Result<Self, ()> {
+    pub fn new(path: impl IntoIterator<Item = impl Into<OsString>>) -> Result<Self, PathError> {
         let mut components = Vec::<RelativePathComponent>::new();
 
         for item in path {
@@ -82,12 +98,12 @@ impl RelativePath {
 
             // 处理 ".." 组件（上级目录）
             if s == ".." {
-                if let Some(last) = components.last() {
-                    if matches!(last, RelativePathComponent::Component(_)) {
-                        // 如果最后一个组件不是 ".."，则移除它（抵消）
-                        components.pop();
-                        continue;
-                    }
+                if let Some(last) = components.last()
+                    && matches!(last, RelativePathComponent::Component(_))
+                {
+                    // 如果最后一个组件不是 ".."，则移除它（抵消）
+                    components.pop();
+                    continue;
                 }
                 // 如果前面没有可以抵消的组件，则添加 ".."
                 components.push(RelativePathComponent::Super);
@@ -95,25 +111,26 @@ impl RelativePath {
             }
 
             // 添加普通组件
-            let s = match crate::file_system::FileName::new(s) {
-                Ok(n) => n,
-                Err(_) => return Err(()),
-            };
+            let s = crate::file_system::FileName::new(s)?;
             components.push(RelativePathComponent::Component(s));
         }
 
         Ok(RelativePath(components))
     }
 
+    /// Appends `component`. Like in [`RelativePath::new`], a `Super` cancels
+    /// the last component instead of being appended after it, so `a` pushed
+    /// with `..` is the empty path rather than `..`.
     pub fn push(&mut self, component: impl Into<RelativePathComponent>) {
         let component = component.into();
         match component {
             RelativePathComponent::Super => {
-                if let Some(last) = self.0.last() {
-                    if matches!(last, RelativePathComponent::Component(_)) {
-                        // 如果最后一个组件不是 ".."，则移除它（抵消）
-                        self.0.pop();
-                    }
+                if let Some(last) = self.0.last()
+                    && matches!(last, RelativePathComponent::Component(_))
+                {
+                    // 如果最后一个组件不是 ".."，则移除它（抵消）
+                    self.0.pop();
+                    return;
                 }
                 // 如果前面没有可以抵消的组件，则添加 ".."
                 self.0.push(RelativePathComponent::Super);
@@ -124,13 +141,13 @@ impl RelativePath {
         }
     }
 
-    pub fn resolve(&self, base_path: impl Into<PathBuf>) -> Result<PathBuf, ()> {
+    pub fn resolve(&self, base_path: impl Into<PathBuf>) -> Result<PathBuf, PathError> {
         let mut path = base_path.into();
         for component in &self.0 {
             match component {
                 RelativePathComponent::Super => {
                     if path.parent().is_none() {
-                        return Err(());
+                        return Err(PathError::OutOfRoot);
                     }
                     path.pop();
                 }
@@ -148,3 +165,432 @@ impl From<FileName> for RelativePath {
         Self(vec![RelativePathComponent::Component(name)])
     }
 }
+
+/// Copies `reader` into `writer`, handing every chunk to `on_data` once it is
+/// written, and returns the number of bytes copied.
+pub fn copy_stream(
+    reader: &mut impl std::io::Read,
+    writer: &mut impl std::io::Write,
+    mut on_data: impl FnMut(&[u8]),
+) -> std::io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut bytes = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(bytes),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        on_data(&buf[..n]);
+        bytes += n as u64;
+    }
+}
+
+/// A failed [`write`] or [`copy_tee`].
+#[derive(Debug)]
+pub struct WriteError {
+    pub source: std::io::Error,
+    /// Whether `to` had already been created or truncated when the write
+    /// failed. Only then is what is left at `to` a partial file of ours.
+    pub touched: bool,
+}
+
+impl WriteError {
+    fn untouched(source: std::io::Error) -> Self {
+        Self {
+            source,
+            touched: false,
+        }
+    }
+
+    fn touched(source: std::io::Error) -> Self {
+        Self {
+            source,
+            touched: true,
+        }
+    }
+}
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.source.fmt(f)
+    }
+}
+
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Writes `contents` to `to` like [`tokio::fs::write`], creating or
+/// truncating it.
+pub async fn write(
+    to: impl AsRef<std::path::Path>,
+    contents: impl AsRef<[u8]>,
+) -> Result<(), WriteError> {
+    use tokio::io::AsyncWriteExt;
+    let mut file = File::create(to).await.map_err(WriteError::untouched)?;
+    file.write_all(contents.as_ref())
+        .await
+        .map_err(WriteError::touched)?;
+    file.flush().await.map_err(WriteError::touched)
+}
+
+/// Copies the file `from` to `to` like [`copy`], permissions included, and
+/// writes its contents into `tee` on the way. `tee` is given back with the
+/// number of bytes copied.
+pub async fn copy_tee<W>(from: PathBuf, to: PathBuf, mut tee: W) -> Result<(u64, W), WriteError>
+where
+    W: std::io::Write + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut reader = std::fs::File::open(&from).map_err(WriteError::untouched)?;
+        let permissions = reader
+            .metadata()
+            .map_err(WriteError::untouched)?
+            .permissions();
+        let mut writer = std::fs::File::create(&to).map_err(WriteError::untouched)?;
+        let mut tee_error = None;
+        let bytes = copy_stream(&mut reader, &mut writer, |data| {
+            if tee_error.is_none() {
+                tee_error = tee.write_all(data).err();
+            }
+        })
+        .map_err(WriteError::touched)?;
+        if let Some(e) = tee_error {
+            return Err(WriteError::touched(e));
+        }
+        writer
+            .set_permissions(permissions)
+            .map_err(WriteError::touched)?;
+        Ok((bytes, tee))
+    })
+    .await
+    .map_err(|e| WriteError::untouched(std::io::Error::other(e)))?
+}
+
+/// Moves `from` to `to`, merging into `to` when both are directories.
+///
+/// Every rename is atomic on its own, so a directory that does not exist yet at
+/// `to` appears all at once. When `from` and `to` are on different filesystems
+/// the entries are copied and then removed instead.
+pub async fn move_tree(from: PathBuf, to: PathBuf) -> std::io::Result<()> {
+    let from_is_dir = symlink_metadata(&from).await?.is_dir();
+    let to_is_dir = symlink_metadata(&to).await.is_ok_and(|m| m.is_dir());
+    if from_is_dir && to_is_dir {
+        let mut read_dir = read_dir(&from).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            Box::pin(move_tree(entry.path(), to.join(entry.file_name()))).await?;
+        }
+        return remove_dir(&from).await;
+    }
+    match rename(&from, &to).await {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_tree(from.clone(), to).await?;
+            if from_is_dir {
+                remove_dir_all(&from).await
+            } else {
+                remove_file(&from).await
+            }
+        }
+        res => res,
+    }
+}
+
+/// Copies `from` to `to` recursively, recreating symbolic links as links.
+pub async fn copy_tree(from: PathBuf, to: PathBuf) -> std::io::Result<()> {
+    let metadata = symlink_metadata(&from).await?;
+    if metadata.is_symlink() {
+        let target = read_link(&from).await?;
+        crate::link::symlink(target, &to).await
+    } else if metadata.is_dir() {
+        create_dir(&to).await?;
+        set_permissions(&to, metadata.permissions()).await?;
+        let mut read_dir = read_dir(&from).await?;
+        while let Some(entry) = read_dir.next_entry().await? {
+            Box::pin(copy_tree(entry.path(), to.join(entry.file_name()))).await?;
+        }
+        Ok(())
+    } else {
+        copy(&from, &to).await.map(|_| ())
+    }
+}
+
+/// Permission bits and ownership to give a path. `None` fields are left as
+/// they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attributes {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Attributes {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.uid.is_none() && self.gid.is_none()
+    }
+
+    /// Overrides the fields that are set in `other`.
+    pub fn merge(&mut self, other: &Attributes) {
+        self.mode = other.mode.or(self.mode);
+        self.uid = other.uid.or(self.uid);
+        self.gid = other.gid.or(self.gid);
+    }
+}
+
+/// Applies `attributes` to `path`. Ownership is changed first, since that can
+/// clear the setuid and setgid bits.
+#[cfg(unix)]
+pub async fn set_attributes(
+    path: &std::path::Path,
+    attributes: &Attributes,
+) -> std::io::Result<()> {
+    if attributes.uid.is_some() || attributes.gid.is_some() {
+        let path = path.to_path_buf();
+        let (uid, gid) = (attributes.uid, attributes.gid);
+        tokio::task::spawn_blocking(move || std::os::unix::fs::lchown(path, uid, gid))
+            .await
+            .map_err(std::io::Error::other)??;
+    }
+    if let Some(mode) = attributes.mode {
+        use std::os::unix::fs::PermissionsExt;
+        set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn set_attributes(_: &std::path::Path, attributes: &Attributes) -> std::io::Result<()> {
+    if attributes.is_empty() {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "unix permissions are not supported on this platform",
+        ))
+    }
+}
+
+/// Looks up the id of the user `name` in the system's user database, which
+/// includes sources such as LDAP next to `/etc/passwd`. A numeric `name` is
+/// taken as the id itself.
+pub fn user_id(name: &str) -> std::io::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    lookup_user(name)
+}
+
+/// Looks up the id of the group `name` in the system's group database. A
+/// numeric `name` is taken as the id itself.
+pub fn group_id(name: &str) -> std::io::Result<u32> {
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+    lookup_group(name)
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> std::io::Result<u32> {
+    let c_name = std::ffi::CString::new(name)?;
+    let mut entry = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    lookup_entry(name, |buf, result| {
+        let result = result as *mut *mut libc::passwd;
+        // SAFETY: every pointer is valid for the duration of the call, and
+        // `buf` is as long as its stated length.
+        unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                entry.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                result,
+            )
+        }
+    })?;
+    // SAFETY: a successful lookup filled in `entry`.
+    Ok(unsafe { entry.assume_init() }.pw_uid)
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> std::io::Result<u32> {
+    let c_name = std::ffi::CString::new(name)?;
+    let mut entry = std::mem::MaybeUninit::<libc::group>::uninit();
+    lookup_entry(name, |buf, result| {
+        let result = result as *mut *mut libc::group;
+        // SAFETY: as in `lookup_user`.
+        unsafe {
+            libc::getgrnam_r(
+                c_name.as_ptr(),
+                entry.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                result,
+            )
+        }
+    })?;
+    // SAFETY: a successful lookup filled in `entry`.
+    Ok(unsafe { entry.assume_init() }.gr_gid)
+}
+
+/// Runs a `get*nam_r` style `lookup`, growing its buffer while it is too
+/// small. `lookup` is given the buffer and where to store the result pointer,
+/// which stays null when there is no entry named `name`.
+#[cfg(unix)]
+fn lookup_entry(
+    name: &str,
+    mut lookup: impl FnMut(&mut [libc::c_char], *mut *mut libc::c_void) -> libc::c_int,
+) -> std::io::Result<()> {
+    let mut buf = vec![0 as libc::c_char; 1024];
+    loop {
+        let mut result = std::ptr::null_mut::<libc::c_void>();
+        match lookup(&mut buf, &mut result) {
+            0 if result.is_null() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("no user or group named {}", name),
+                ));
+            }
+            0 => return Ok(()),
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            code => return Err(std::io::Error::from_raw_os_error(code)),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lookup_user(_: &str) -> std::io::Result<u32> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "users are not supported on this platform",
+    ))
+}
+
+#[cfg(not(unix))]
+fn lookup_group(_: &str) -> std::io::Result<u32> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "groups are not supported on this platform",
+    ))
+}
+
+/// Returns the closest ancestor of `path`, or `path` itself, that exists.
+pub fn existing_ancestor(path: &std::path::Path) -> Option<&std::path::Path> {
+    path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && std::fs::symlink_metadata(p).is_ok())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+#[cfg(unix)]
+pub fn available_space(path: &std::path::Path) -> std::io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `path` is a valid C string and `stat` is only read on success.
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_: &std::path::Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "free space cannot be queried on this platform",
+    ))
+}
+
+/// Identifies the filesystem holding `path`, so that paths can be grouped by
+/// the space they share.
+#[cfg(unix)]
+pub fn filesystem_id(path: &std::path::Path) -> std::io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::metadata(path)?.dev())
+}
+
+#[cfg(not(unix))]
+pub fn filesystem_id(_: &std::path::Path) -> std::io::Result<u64> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "filesystems cannot be identified on this platform",
+    ))
+}
+
+/// Whether the current user can create entries in the directory `path`.
+#[cfg(unix)]
+pub fn is_writable_dir(path: &std::path::Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is a valid C string.
+    unsafe {
+        libc::faccessat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            libc::W_OK | libc::X_OK,
+            libc::AT_EACCESS,
+        ) == 0
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_writable_dir(path: &std::path::Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|m| !m.permissions().readonly())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(s: &str) -> FileName {
+        FileName::new(s.into()).unwrap()
+    }
+
+    #[test]
+    fn pushing_super_cancels_the_last_component() {
+        let mut path = RelativePath::from(name("a"));
+        path.push(name("b"));
+        path.push(RelativePathComponent::Super);
+        assert_eq!(path.resolve("/base").unwrap(), PathBuf::from("/base/a"));
+        path.push(RelativePathComponent::Super);
+        assert_eq!(path.resolve("/base").unwrap(), PathBuf::from("/base"));
+        path.push(RelativePathComponent::Super);
+        assert_eq!(path.resolve("/base/x").unwrap(), PathBuf::from("/base"));
+
+        let parsed = RelativePath::new(["a", "b", "..", "..", ".."]).unwrap();
+        assert_eq!(
+            parsed.resolve("/base/x").unwrap(),
+            path.resolve("/base/x").unwrap()
+        );
+    }
+
+    #[test]
+    fn resolving_above_the_root_fails() {
+        let path = RelativePath::new(["..", ".."]).unwrap();
+        assert!(matches!(path.resolve("/a"), Err(PathError::OutOfRoot)));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn users_and_groups_are_looked_up() {
+        assert_eq!(user_id("root").unwrap(), 0);
+        assert_eq!(group_id("42").unwrap(), 42);
+        let missing = "veridian-no-such-user";
+        assert_eq!(
+            user_id(missing).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(
+            group_id(missing).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,3 +1,4 @@
+pub mod archive;
 pub mod env;
 pub mod file_system;
 pub mod link;
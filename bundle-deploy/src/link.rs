@@ -0,0 +1,144 @@
+use std::io;
+use std::path::Path;
+
+/// Creates a symbolic link at `link` pointing to `original`.
+pub async fn symlink(original: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        tokio::fs::symlink(original, link).await
+    }
+    #[cfg(windows)]
+    {
+        if tokio::fs::metadata(original.as_ref()).await?.is_dir() {
+            tokio::fs::symlink_dir(original, link).await
+        } else {
+            tokio::fs::symlink_file(original, link).await
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (original, link);
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Creates a hard link at `link` pointing to `original`.
+pub async fn hard_link(original: impl AsRef<Path>, link: impl AsRef<Path>) -> io::Result<()> {
+    tokio::fs::hard_link(original, link).await
+}
+
+/// Creates a desktop shortcut at `shortcut` launching `target`. Like
+/// [`symlink`] and [`hard_link`], this fails with
+/// [`io::ErrorKind::AlreadyExists`] if `shortcut` already exists.
+///
+/// On Linux this is a freedesktop `.desktop` entry whose `Name` is taken from
+/// the file stem of `shortcut`. Other platforms are not supported yet.
+pub async fn shortcut(target: impl AsRef<Path>, shortcut: impl AsRef<Path>) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let target = target.as_ref();
+        let shortcut = shortcut.as_ref();
+        let name = shortcut
+            .file_stem()
+            .or_else(|| target.file_stem())
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut entry = String::with_capacity(128);
+        entry.push_str("[Desktop Entry]\n");
+        entry.push_str("Type=Application\n");
+        entry.push_str(&format!("Name={}\n", name));
+        entry.push_str(&format!("Exec={}\n", quote_exec_arg(target)));
+        if let Some(dir) = target.parent() {
+            entry.push_str(&format!("Path={}\n", dir.display()));
+        }
+        entry.push_str("Terminal=false\n");
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::io::AsyncWriteExt;
+        let mut file = tokio::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(shortcut)
+            .await?;
+        file.write_all(entry.as_bytes()).await?;
+        file.set_permissions(std::fs::Permissions::from_mode(0o755))
+            .await
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (target, shortcut);
+        Err(io::Error::from(io::ErrorKind::Unsupported))
+    }
+}
+
+/// Quotes an argument of the `Exec` key following the desktop entry spec.
+#[cfg(target_os = "linux")]
+fn quote_exec_arg(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for c in path.chars() {
+        match c {
+            '"' | '`' | '$' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '%' => quoted.push_str("%%"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "bundle-deploy-link-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn shortcuts_are_executable_desktop_entries() {
+        let dir = temp_dir("shortcut");
+        let target = dir.join("bin/my \"app\"");
+        let entry = dir.join("My App.desktop");
+        block_on(shortcut(&target, &entry)).unwrap();
+
+        let contents = std::fs::read_to_string(&entry).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        assert!(lines.contains(&"Name=My App"));
+        let exec = format!("Exec=\"{}/bin/my \\\"app\\\"\"", dir.display());
+        assert!(lines.contains(&exec.as_str()), "{}", contents);
+        let path = format!("Path={}/bin", dir.display());
+        assert!(lines.contains(&path.as_str()), "{}", contents);
+        let mode = std::fs::metadata(&entry).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+    }
+
+    #[test]
+    fn existing_shortcuts_are_not_overwritten() {
+        let dir = temp_dir("existing");
+        let entry = dir.join("app.desktop");
+        std::fs::write(&entry, "mine").unwrap();
+        let err = block_on(shortcut(dir.join("app"), &entry)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(&entry).unwrap(), "mine");
+    }
+}
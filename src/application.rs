@@ -1,28 +1,75 @@
 use crate::recorder;
+use serde::Serialize;
+use std::path::PathBuf;
 use uuid::Uuid;
 
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Metadata {
+    pub name: String,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub publisher: Option<String>,
+    pub homepage: Option<String>,
+    /// Name of the profile the application was installed into.
+    pub profile: String,
+    /// Unix timestamp in seconds, set when the install finishes.
+    pub installed_at: i64,
+    /// Path of the script the application was installed from.
+    pub script: PathBuf,
+}
+
+impl Metadata {
+    pub fn installed_at_rfc3339(&self) -> String {
+        time::OffsetDateTime::from_unix_timestamp(self.installed_at)
+            .ok()
+            .and_then(|t| {
+                t.format(&time::format_description::well_known::Rfc3339)
+                    .ok()
+            })
+            .unwrap_or_else(|| self.installed_at.to_string())
+    }
+}
+
 pub struct Application {
     id: Uuid,
+    metadata: Metadata,
     recorder: recorder::Recorder,
 }
 
 impl Application {
-    pub fn new(id: Uuid, recorder: recorder::Recorder) -> Self {
-        Self { id, recorder }
+    pub fn new(id: Uuid, metadata: Metadata, recorder: recorder::Recorder) -> Self {
+        Self {
+            id,
+            metadata,
+            recorder,
+        }
     }
 
     pub fn id(&self) -> Uuid {
         self.id
     }
 
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
     pub fn recorder(&self) -> &recorder::Recorder {
         &self.recorder
     }
+
+    pub fn into_recorder(self) -> recorder::Recorder {
+        self.recorder
+    }
 }
 
 impl From<recorder::Recorder> for Application {
     fn from(recorder: recorder::Recorder) -> Self {
         let id = Uuid::new_v4();
-        Self { id, recorder }
+        let metadata = Metadata::default();
+        Self {
+            id,
+            metadata,
+            recorder,
+        }
     }
 }
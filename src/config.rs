@@ -1,36 +1,849 @@
+use crate::dir_path;
+use bundle_deploy::env::{EnvFileFormat, EnvTarget};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// The profile used when none is given on the command line.
+    #[serde(rename = "default-profile")]
+    pub default_profile: String,
     pub profiles: HashMap<String, Profile>,
 }
 
 impl Config {
-    pub fn from_toml(path: &str) -> Result<Self, toml::de::Error> {
-        toml::from_str(path)
+    pub fn profile(&self, name: &str) -> Result<&Profile, ConfigErr> {
+        self.profiles
+            .get(name)
+            .ok_or_else(|| ConfigErr::UnknownProfile(name.to_string()))
+    }
+
+    fn validate(&mut self) -> Result<(), ConfigErr> {
+        for (name, profile) in &mut self.profiles {
+            // The name is part of the database file name.
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(ConfigErr::Invalid(format!(
+                    "invalid profile name `{}`, expected letters, digits, `-` and `_`",
+                    name
+                )));
+            }
+            profile.name = name.clone();
+        }
+        self.profile(&self.default_profile)?;
+        Ok(())
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
-        let (personal, global) = bundle_deploy::env::get_software_install_paths();
+        Self::defaults(
+            bundle_deploy::env::InstallLocations::current(),
+            dir_path::root(),
+        )
+    }
+}
+
+impl Config {
+    /// The built-in configuration for `locations`. With a `root`, such as the
+    /// one `dir_path::root` returns, every profile is kept under
+    /// `root/<profile>` instead, so that nothing outside the root is touched
+    /// unless configured; such profiles need no root rights.
+    fn defaults(locations: bundle_deploy::env::InstallLocations, root: Option<&Path>) -> Self {
+        let mut profiles = HashMap::with_capacity(2);
+        if let Some(root) = root {
+            for name in ["personal", "global"] {
+                profiles.insert(name.to_string(), Profile::under(name, &root.join(name)));
+            }
+            return Self {
+                default_profile: "personal".to_string(),
+                profiles,
+            };
+        }
         let personal = Profile {
-            default_install_path: PathBuf::from(personal),
+            name: "personal".to_string(),
+            bin_dir: locations.user_bin,
+            desktop_dir: locations.user_applications,
+            icon_dir: locations.user_root.join("icons"),
+            env_file: locations.home.join(".profile"),
+            env_format: EnvFormat::Shell,
+            requires_root: false,
+            default_install_path: locations.user_root,
         };
-        let global = Profile {
-            default_install_path: PathBuf::from(global),
+        let global = if cfg!(windows) {
+            Profile {
+                name: "global".to_string(),
+                bin_dir: locations.global_bin,
+                desktop_dir: locations.global_root.join("shortcuts"),
+                icon_dir: locations.global_root.join("icons"),
+                env_file: locations.global_root.join("env.sh"),
+                env_format: EnvFormat::Shell,
+                requires_root: true,
+                default_install_path: locations.global_root,
+            }
+        } else {
+            Profile {
+                name: "global".to_string(),
+                bin_dir: locations.global_bin,
+                desktop_dir: PathBuf::from("/usr/local/share/applications"),
+                icon_dir: PathBuf::from("/usr/local/share/icons"),
+                env_file: PathBuf::from("/etc/profile.d/veridian-manager.sh"),
+                env_format: EnvFormat::Shell,
+                requires_root: true,
+                default_install_path: locations.global_root,
+            }
         };
-        let mut profiles = HashMap::with_capacity(2);
         profiles.insert("personal".to_string(), personal);
         profiles.insert("global".to_string(), global);
-        Self { profiles }
+        Self {
+            default_profile: "personal".to_string(),
+            profiles,
+        }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A profile read from the configuration. Only `default-install-path` is
+/// required: the directories left out default to `bin`, `applications` and
+/// `icons` under it, and the env file to `env.sh` there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "ProfileLayer")]
 pub struct Profile {
+    /// The key of the profile in `Config::profiles`.
+    #[serde(skip)]
+    pub name: String,
     #[serde(rename = "default-install-path")]
     pub default_install_path: PathBuf,
+    /// Where launchers of installed programs are linked.
+    #[serde(rename = "bin-dir")]
+    pub bin_dir: PathBuf,
+    /// Where desktop entries are written.
+    #[serde(rename = "desktop-dir")]
+    pub desktop_dir: PathBuf,
+    #[serde(rename = "icon-dir")]
+    pub icon_dir: PathBuf,
+    /// The file environment changes such as `prepend_path` are written to.
+    #[serde(rename = "env-file")]
+    pub env_file: PathBuf,
+    #[serde(rename = "env-format")]
+    pub env_format: EnvFormat,
+    /// Whether installing into the profile needs root rights. Installs are
+    /// refused up front without them on Unix; elsewhere it is not checked and
+    /// writes fail on their own without the rights.
+    #[serde(rename = "requires-root")]
+    pub requires_root: bool,
+}
+
+impl Profile {
+    /// A profile keeping everything in `dir`.
+    fn under(name: &str, dir: &Path) -> Self {
+        Self {
+            name: name.to_string(),
+            default_install_path: dir.join("install"),
+            bin_dir: dir.join("bin"),
+            desktop_dir: dir.join("applications"),
+            icon_dir: dir.join("icons"),
+            env_file: dir.join("env.sh"),
+            env_format: EnvFormat::Shell,
+            requires_root: false,
+        }
+    }
+
+    /// A profile installing into `path`, with every other directory under it.
+    fn with_install_path(path: PathBuf) -> Self {
+        Self {
+            name: String::new(),
+            bin_dir: path.join("bin"),
+            desktop_dir: path.join("applications"),
+            icon_dir: path.join("icons"),
+            env_file: path.join("env.sh"),
+            env_format: EnvFormat::Shell,
+            requires_root: false,
+            default_install_path: path,
+        }
+    }
+
+    pub fn env_target(&self) -> EnvTarget {
+        let format = match self.env_format {
+            EnvFormat::Shell => EnvFileFormat::Shell,
+            EnvFormat::EnvironmentD => EnvFileFormat::EnvironmentD,
+        };
+        EnvTarget::new(self.env_file.clone(), format)
+    }
+}
+
+/// The syntax of a profile's `env-file`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnvFormat {
+    /// A POSIX shell script such as `~/.profile`.
+    #[default]
+    Shell,
+    /// A systemd `environment.d` drop-in file.
+    EnvironmentD,
+}
+
+/// What a single layer may set. Every key is optional so that a layer only
+/// overrides what it mentions, and unknown keys are rejected so that typos
+/// are reported instead of ignored. Serializing a layer gives back only the
+/// keys it set.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigLayer {
+    #[serde(rename = "default-profile", skip_serializing_if = "Option::is_none")]
+    default_profile: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    profiles: HashMap<String, ProfileLayer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileLayer {
+    #[serde(
+        rename = "default-install-path",
+        skip_serializing_if = "Option::is_none"
+    )]
+    default_install_path: Option<PathBuf>,
+    #[serde(rename = "bin-dir", skip_serializing_if = "Option::is_none")]
+    bin_dir: Option<PathBuf>,
+    #[serde(rename = "desktop-dir", skip_serializing_if = "Option::is_none")]
+    desktop_dir: Option<PathBuf>,
+    #[serde(rename = "icon-dir", skip_serializing_if = "Option::is_none")]
+    icon_dir: Option<PathBuf>,
+    #[serde(rename = "env-file", skip_serializing_if = "Option::is_none")]
+    env_file: Option<PathBuf>,
+    #[serde(rename = "env-format", skip_serializing_if = "Option::is_none")]
+    env_format: Option<EnvFormat>,
+    #[serde(rename = "requires-root", skip_serializing_if = "Option::is_none")]
+    requires_root: Option<bool>,
+}
+
+impl TryFrom<ProfileLayer> for Profile {
+    type Error = String;
+
+    fn try_from(layer: ProfileLayer) -> Result<Self, Self::Error> {
+        let path = layer
+            .default_install_path
+            .ok_or("missing field `default-install-path`")?;
+        let defaults = Profile::with_install_path(path);
+        Ok(Self {
+            bin_dir: layer.bin_dir.unwrap_or(defaults.bin_dir),
+            desktop_dir: layer.desktop_dir.unwrap_or(defaults.desktop_dir),
+            icon_dir: layer.icon_dir.unwrap_or(defaults.icon_dir),
+            env_file: layer.env_file.unwrap_or(defaults.env_file),
+            env_format: layer.env_format.unwrap_or_default(),
+            requires_root: layer.requires_root.unwrap_or_default(),
+            ..defaults
+        })
+    }
+}
+
+/// Top-level keys that `VERIDIAN_*` environment variables can set. Other
+/// variables with the prefix are not configuration and are left alone.
+const ENV_KEYS: &[&str] = &["default-profile", "profiles"];
+const ENV_PREFIX: &str = "VERIDIAN_";
+
+/// Where a configuration value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    /// The name of the environment variable.
+    Env(String),
+    /// A `--config KEY=VALUE` flag.
+    Cli,
+}
+
+impl Display for ConfigSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Default => f.write_str("default"),
+            ConfigSource::File(path) => f.write_fmt(format_args!("{}", path.display())),
+            ConfigSource::Env(name) => f.write_fmt(format_args!("environment variable {}", name)),
+            ConfigSource::Cli => f.write_str("--config"),
+        }
+    }
+}
+
+/// The merged configuration together with the source of every value, keyed
+/// by dotted path such as `profiles.personal.default-install-path`.
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub sources: BTreeMap<String, ConfigSource>,
+    /// Problems that did not stop the configuration from loading, such as
+    /// values written by older versions that were ignored.
+    pub warnings: Vec<String>,
+}
+
+impl LoadedConfig {
+    /// Every value of the merged configuration with its dotted key, in key
+    /// order.
+    pub fn values(&self) -> Vec<(String, toml::Value)> {
+        let mut values = Vec::new();
+        if let Ok(table) = toml::Table::try_from(&self.config) {
+            flatten(String::new(), table, &mut values);
+        }
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        values
+    }
+}
+
+/// Loads the configuration from layers, each overriding the ones before it:
+/// the built-in defaults, then files, then environment variables, then
+/// command line overrides.
+#[derive(Debug, Default)]
+pub struct ConfigLoader {
+    files: Vec<PathBuf>,
+    env: Vec<(String, String)>,
+    /// `VERIDIAN_*` variables whose value is not valid Unicode.
+    invalid_env: Vec<String>,
+    overrides: Vec<String>,
+}
+
+impl ConfigLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A loader for the system file, the user file and the `VERIDIAN_*`
+    /// variables of this process. The system file is left out when
+    /// `dir_path::root` moves the user directories, so that such a root is
+    /// self-contained.
+    pub fn standard() -> Self {
+        let mut loader = Self::new();
+        #[cfg(unix)]
+        if dir_path::root().is_none() {
+            loader.add_file(PathBuf::from("/etc/veridian-manager/config.toml"));
+        }
+        loader.add_file(dir_path::config().join("config.toml"));
+        for (name, value) in std::env::vars_os() {
+            loader.add_env_os(name, value);
+        }
+        loader
+    }
+
+    /// Adds a file layer. Files that do not exist are skipped.
+    pub fn add_file(&mut self, path: PathBuf) {
+        self.files.push(path);
+    }
+
+    /// Adds an environment variable. Only `VERIDIAN_*` variables naming a
+    /// configuration key are used: the key is upper-cased, with `.` written as
+    /// `__` and `-` as `_`, so `VERIDIAN_PROFILES__PERSONAL__DEFAULT_INSTALL_PATH`
+    /// sets `profiles.personal.default-install-path`. Profile names are kept
+    /// as written apart from case, so `_` in `VERIDIAN_PROFILES__MY_WORK__...`
+    /// stays in the profile name `my_work`.
+    pub fn add_env(&mut self, name: String, value: String) {
+        self.env.push((name, value));
+    }
+
+    /// Adds an environment variable like `add_env`, from a name and value that
+    /// need not be valid Unicode. Variables without the `VERIDIAN_` prefix are
+    /// skipped, and `load` fails if one naming a configuration key has a
+    /// value that is not valid Unicode.
+    pub fn add_env_os(&mut self, name: OsString, value: OsString) {
+        let Some(name) = name
+            .into_string()
+            .ok()
+            .filter(|n| n.starts_with(ENV_PREFIX))
+        else {
+            return;
+        };
+        match value.into_string() {
+            Ok(value) => self.add_env(name, value),
+            Err(_) => self.invalid_env.push(name),
+        }
+    }
+
+    /// Adds a `KEY=VALUE` override, where `KEY` is a dotted key.
+    pub fn add_override(&mut self, assignment: String) {
+        self.overrides.push(assignment);
+    }
+
+    pub fn load(&self) -> Result<LoadedConfig, ConfigErr> {
+        let mut merged =
+            toml::Table::try_from(Config::default()).expect("default config is serializable");
+        let mut sources = BTreeMap::new();
+        let mut warnings = Vec::new();
+        let mut defaults = Vec::new();
+        flatten(String::new(), merged.clone(), &mut defaults);
+        for (key, _) in defaults {
+            sources.insert(key, ConfigSource::Default);
+        }
+        for path in &self.files {
+            let text = match std::fs::read_to_string(path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(source) => {
+                    return Err(ConfigErr::Read {
+                        path: path.clone(),
+                        source,
+                    });
+                }
+            };
+            let source = ConfigSource::File(path.clone());
+            let mut layer = parse_layer(&text, &source)?;
+            if drop_legacy_values(&mut layer) {
+                warnings.push(format!(
+                    "{}: ignoring `{} = \"{}\"`, the default written by earlier versions; \
+                     remove it from the file",
+                    path.display(),
+                    LEGACY_GLOBAL_INSTALL_PATH.0,
+                    LEGACY_GLOBAL_INSTALL_PATH.1
+                ));
+            }
+            merge_layer(&mut merged, &mut sources, layer, source);
+        }
+        if let Some(name) = self.invalid_env.iter().find(|n| env_key(n).is_some()) {
+            return Err(ConfigErr::NotUnicode(name.clone()));
+        }
+        let mut env: Vec<_> = self
+            .env
+            .iter()
+            .filter_map(|(name, value)| Some((name, env_key(name)?, value)))
+            .collect();
+        env.sort();
+        for (name, key, value) in env {
+            let text = assignment(&key, value);
+            apply_layer(
+                &mut merged,
+                &mut sources,
+                &text,
+                ConfigSource::Env(name.clone()),
+            )?;
+        }
+        for override_ in &self.overrides {
+            let Some((key, value)) = override_.split_once('=') else {
+                return Err(ConfigErr::InvalidOverride(override_.clone()));
+            };
+            let key = key.trim();
+            let is_bare_key = |s: &str| {
+                !s.is_empty()
+                    && s.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            };
+            if !key.split('.').all(is_bare_key) {
+                return Err(ConfigErr::InvalidOverride(override_.clone()));
+            }
+            let text = assignment(key, value);
+            apply_layer(&mut merged, &mut sources, &text, ConfigSource::Cli)?;
+        }
+        let mut config =
+            Config::deserialize(merged).map_err(|e| ConfigErr::Invalid(e.to_string()))?;
+        config.validate()?;
+        Ok(LoadedConfig {
+            config,
+            sources,
+            warnings,
+        })
+    }
+}
+
+/// Checks `text` as a layer and merges it into `merged`.
+fn apply_layer(
+    merged: &mut toml::Table,
+    sources: &mut BTreeMap<String, ConfigSource>,
+    text: &str,
+    source: ConfigSource,
+) -> Result<(), ConfigErr> {
+    let layer = parse_layer(text, &source)?;
+    merge_layer(merged, sources, layer, source);
+    Ok(())
+}
+
+/// Parses `text` as a layer, rejecting unknown keys and values of the wrong
+/// type.
+fn parse_layer(text: &str, source: &ConfigSource) -> Result<toml::Table, ConfigErr> {
+    let parse_err = |e: toml::de::Error| {
+        // Line and column only mean something for files. Environment variables
+        // and overrides are turned into one-line documents here.
+        let span = e.span().filter(|_| matches!(source, ConfigSource::File(_)));
+        let (line, column) = match span {
+            Some(span) => {
+                let before = &text[..span.start];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                (Some(line), Some(column))
+            }
+            None => (None, None),
+        };
+        ConfigErr::Parse {
+            source: source.clone(),
+            line,
+            column,
+            message: e.message().to_string(),
+        }
+    };
+    let layer = toml::from_str::<ConfigLayer>(text).map_err(parse_err)?;
+    Ok(toml::Table::try_from(layer).expect("config layer is serializable"))
+}
+
+/// Merges `layer` into `merged`, attributing every value it sets to `source`.
+fn merge_layer(
+    merged: &mut toml::Table,
+    sources: &mut BTreeMap<String, ConfigSource>,
+    layer: toml::Table,
+    source: ConfigSource,
+) {
+    let mut keys = Vec::new();
+    flatten(String::new(), layer.clone(), &mut keys);
+    for (key, _) in keys {
+        sources.insert(key, source.clone());
+    }
+    merge(merged, layer);
+}
+
+/// The value earlier versions wrote into every new user configuration file on
+/// Linux. Applications do not belong directly in `/usr/bin`, so it is ignored
+/// in favour of the current default.
+const LEGACY_GLOBAL_INSTALL_PATH: (&str, &str) =
+    ("profiles.global.default-install-path", "/usr/bin");
+
+/// Removes the values of `LEGACY_GLOBAL_INSTALL_PATH` from a file layer,
+/// returning whether there was one.
+fn drop_legacy_values(layer: &mut toml::Table) -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+    let Some(toml::Value::Table(global)) = layer
+        .get_mut("profiles")
+        .and_then(|p| p.as_table_mut())
+        .and_then(|p| p.get_mut("global"))
+    else {
+        return false;
+    };
+    let legacy = global
+        .get("default-install-path")
+        .and_then(|v| v.as_str())
+        .is_some_and(|v| v == LEGACY_GLOBAL_INSTALL_PATH.1);
+    if legacy {
+        global.remove("default-install-path");
+    }
+    legacy
+}
+
+fn merge(into: &mut toml::Table, from: toml::Table) {
+    for (key, value) in from {
+        match (into.get_mut(&key), value) {
+            (Some(toml::Value::Table(into)), toml::Value::Table(from)) => merge(into, from),
+            (_, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+fn flatten(prefix: String, table: toml::Table, out: &mut Vec<(String, toml::Value)>) {
+    for (key, value) in table {
+        let key = if prefix.is_empty() {
+            key
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+        match value {
+            toml::Value::Table(table) => flatten(key, table, out),
+            value => out.push((key, value)),
+        }
+    }
+}
+
+/// The dotted key an environment variable sets, if it is one of ours.
+fn env_key(name: &str) -> Option<String> {
+    let name = name.strip_prefix(ENV_PREFIX)?.to_ascii_lowercase();
+    let segments: Vec<_> = name.split("__").collect();
+    let top = segments[0].replace('_', "-");
+    if !ENV_KEYS.contains(&top.as_str()) {
+        return None;
+    }
+    let key: Vec<_> = segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| match i {
+            // Profile names are taken as written, `_` included.
+            1 if top == "profiles" => segment.to_string(),
+            _ => segment.replace('_', "-"),
+        })
+        .collect();
+    Some(key.join("."))
+}
+
+/// A TOML document assigning `value` to `key`. Values that are not valid TOML
+/// on their own, such as bare paths, are taken as strings.
+fn assignment(key: &str, value: &str) -> String {
+    let value = value.trim();
+    if toml::from_str::<toml::Table>(&format!("v = {}", value)).is_ok() {
+        format!("{} = {}", key, value)
+    } else {
+        format!("{} = {}", key, toml::Value::String(value.to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigErr {
+    Read {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A layer is not valid TOML or sets an unknown key or a value of the
+    /// wrong type. `line` and `column` are 1-based.
+    Parse {
+        source: ConfigSource,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+    },
+    /// A command line override that is not `KEY=VALUE`.
+    InvalidOverride(String),
+    /// The named environment variable sets a configuration key, but its value
+    /// is not valid Unicode.
+    NotUnicode(String),
+    /// The merged layers do not form a complete configuration.
+    Invalid(String),
+    UnknownProfile(String),
+}
+
+impl Display for ConfigErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigErr::Read { path, source } => {
+                f.write_fmt(format_args!("{}: {}", path.display(), source))
+            }
+            ConfigErr::Parse {
+                source,
+                line: Some(line),
+                column: Some(column),
+                message,
+            } => f.write_fmt(format_args!(
+                "{}:{}:{}: {}",
+                source,
+                line,
+                column,
+                message.trim()
+            )),
+            ConfigErr::Parse {
+                source, message, ..
+            } => f.write_fmt(format_args!("{}: {}", source, message.trim())),
+            ConfigErr::InvalidOverride(assignment) => f.write_fmt(format_args!(
+                "invalid override `{}`, expected KEY=VALUE",
+                assignment
+            )),
+            ConfigErr::NotUnicode(name) => f.write_fmt(format_args!(
+                "environment variable {} is not valid unicode",
+                name
+            )),
+            ConfigErr::Invalid(message) => f.write_str(message.trim()),
+            ConfigErr::UnknownProfile(name) => {
+                f.write_fmt(format_args!("no profile named `{}`", name))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigErr::Read { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let path = std::env::temp_dir().join(format!(
+            "veridian-config-layers-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "[profiles.work]\n\
+             default-install-path = \"/file/work\"\n\
+             [profiles.lab]\n\
+             default-install-path = \"/file/lab\"\n",
+        )
+        .unwrap();
+        let mut loader = ConfigLoader::new();
+        loader.add_file(path.clone());
+        loader.add_env(
+            "VERIDIAN_PROFILES__WORK__DEFAULT_INSTALL_PATH".to_string(),
+            "/env/work".to_string(),
+        );
+        loader.add_env(
+            "VERIDIAN_PROFILES__LAB__DEFAULT_INSTALL_PATH".to_string(),
+            "/env/lab".to_string(),
+        );
+        loader.add_env("VERIDIAN_HOME".to_string(), "/elsewhere".to_string());
+        loader.add_override("profiles.lab.default-install-path=/cli/lab".to_string());
+        let loaded = loader.load();
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        let install_path = |name: &str| &loaded.config.profiles[name].default_install_path;
+        assert_eq!(install_path("work"), Path::new("/env/work"));
+        assert_eq!(install_path("lab"), Path::new("/cli/lab"));
+
+        let source = |key: &str| loaded.sources.get(key).cloned();
+        assert_eq!(
+            source("profiles.work.default-install-path"),
+            Some(ConfigSource::Env(
+                "VERIDIAN_PROFILES__WORK__DEFAULT_INSTALL_PATH".to_string()
+            ))
+        );
+        assert_eq!(
+            source("profiles.lab.default-install-path"),
+            Some(ConfigSource::Cli)
+        );
+        assert_eq!(
+            source("profiles.personal.default-install-path"),
+            Some(ConfigSource::Default)
+        );
+        assert_eq!(source("home"), None);
+    }
+
+    #[test]
+    fn profile_directories_default_to_the_install_path() {
+        let mut loader = ConfigLoader::new();
+        loader.add_override("profiles.work.default-install-path=/srv/work".to_string());
+        loader.add_override("profiles.work.icon-dir=/srv/icons".to_string());
+        let loaded = loader.load().unwrap();
+        let work = loaded.config.profile("work").unwrap();
+        assert_eq!(work.name, "work");
+        assert_eq!(work.bin_dir, PathBuf::from("/srv/work/bin"));
+        assert_eq!(work.desktop_dir, PathBuf::from("/srv/work/applications"));
+        assert_eq!(work.icon_dir, PathBuf::from("/srv/icons"));
+        assert_eq!(work.env_file, PathBuf::from("/srv/work/env.sh"));
+        assert_eq!(work.env_format, EnvFormat::Shell);
+        assert!(!work.requires_root);
+
+        let mut loader = ConfigLoader::new();
+        loader.add_override("profiles.work.bin-dir=/srv/bin".to_string());
+        let err = loader.load().unwrap_err();
+        assert!(err.to_string().contains("default-install-path"), "{}", err);
+    }
+
+    #[test]
+    fn environment_variables_keep_underscores_in_profile_names() {
+        assert_eq!(
+            env_key("VERIDIAN_PROFILES__MY_WORK__DEFAULT_INSTALL_PATH").as_deref(),
+            Some("profiles.my_work.default-install-path")
+        );
+        assert_eq!(env_key("VERIDIAN_HOME"), None);
+        assert_eq!(env_key("PROFILES__WORK__DEFAULT_INSTALL_PATH"), None);
+
+        let mut loader = ConfigLoader::new();
+        loader.add_env(
+            "VERIDIAN_PROFILES__MY_WORK__DEFAULT_INSTALL_PATH".to_string(),
+            "/srv/work".to_string(),
+        );
+        let loaded = loader.load().unwrap();
+        assert_eq!(
+            loaded.config.profiles["my_work"].default_install_path,
+            Path::new("/srv/work")
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn environment_values_that_are_not_unicode() {
+        use std::os::unix::ffi::OsStringExt;
+        let invalid = || OsString::from_vec(vec![0xff]);
+        let mut loader = ConfigLoader::new();
+        loader.add_env_os(OsString::from("BADVAR"), invalid());
+        loader.add_env_os(OsString::from_vec(b"VERIDIAN_\xff".to_vec()), invalid());
+        loader.add_env_os(OsString::from("VERIDIAN_HOME"), invalid());
+        loader.add_env_os(
+            OsString::from("VERIDIAN_PROFILES__WORK__DEFAULT_INSTALL_PATH"),
+            OsString::from("/srv/work"),
+        );
+        let loaded = loader.load().unwrap();
+        assert_eq!(
+            loaded.config.profiles["work"].default_install_path,
+            Path::new("/srv/work")
+        );
+
+        loader.add_env_os(
+            OsString::from("VERIDIAN_PROFILES__LAB__DEFAULT_INSTALL_PATH"),
+            invalid(),
+        );
+        match loader.load() {
+            Err(ConfigErr::NotUnicode(name)) => {
+                assert_eq!(name, "VERIDIAN_PROFILES__LAB__DEFAULT_INSTALL_PATH")
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_reported_with_their_source() {
+        let mut loader = ConfigLoader::new();
+        loader.add_override("profiles.work.install-path=/srv/work".to_string());
+        match loader.load() {
+            Err(ConfigErr::Parse {
+                source: ConfigSource::Cli,
+                line: None,
+                ..
+            }) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let mut loader = ConfigLoader::new();
+        loader.add_env(
+            "VERIDIAN_PROFILES__WORK__DEFAULT_INSTALL_PATH".to_string(),
+            "[1, 2]".to_string(),
+        );
+        match loader.load() {
+            Err(ConfigErr::Parse {
+                source: ConfigSource::Env(name),
+                ..
+            }) => assert_eq!(name, "VERIDIAN_PROFILES__WORK__DEFAULT_INSTALL_PATH"),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn profiles_default_to_the_root() {
+        let root =
+            std::env::temp_dir().join(format!("veridian-config-root-{}", std::process::id()));
+        let locations = bundle_deploy::env::InstallLocations::from_env(|_| None);
+        let config = Config::defaults(locations, Some(&root));
+        for (name, profile) in &config.profiles {
+            let dir = root.join(name);
+            assert_eq!(profile.default_install_path, dir.join("install"));
+            assert_eq!(profile.bin_dir, dir.join("bin"));
+            assert_eq!(profile.desktop_dir, dir.join("applications"));
+            assert_eq!(profile.icon_dir, dir.join("icons"));
+            assert_eq!(profile.env_file, dir.join("env.sh"));
+            assert!(!profile.requires_root);
+        }
+        assert_eq!(config.profiles.len(), 2);
+        assert!(config.profiles.contains_key(&config.default_profile));
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn legacy_global_install_path_is_ignored() {
+        let mut layer: toml::Table = toml::from_str(
+            "[profiles.global]\ndefault-install-path = \"/usr/bin\"\nbin-dir = \"/b\"\n",
+        )
+        .unwrap();
+        assert!(drop_legacy_values(&mut layer));
+        let global = layer["profiles"]["global"].as_table().unwrap();
+        assert!(!global.contains_key("default-install-path"));
+        assert_eq!(global["bin-dir"].as_str(), Some("/b"));
+
+        let mut layer: toml::Table =
+            toml::from_str("[profiles.global]\ndefault-install-path = \"/srv\"\n").unwrap();
+        assert!(!drop_legacy_values(&mut layer));
+    }
 }
@@ -1,58 +1,796 @@
 use crate::{application, recorder};
-use sqlite::ConnectionThreadSafe;
+use sqlite::{ConnectionThreadSafe, Statement};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
+enum Migration {
+    Sql(&'static str),
+    /// A step that needs data only the program can decode, such as recorders.
+    Code(fn(&Database) -> DatabaseResult<()>),
+}
+
+/// Schema migrations, applied in order. The database's `user_version` is the
+/// number of steps already applied, so steps must never be edited or removed.
+const MIGRATIONS: &[Migration] = &[
+    Migration::Sql(
+        "CREATE TABLE IF NOT EXISTS application( \
+    id TEXT PRIMARY KEY, \
+    recorder BLOB NOT NULL \
+    )",
+    ),
+    Migration::Sql(
+        "ALTER TABLE application ADD COLUMN name TEXT NOT NULL DEFAULT ''; \
+    ALTER TABLE application ADD COLUMN version TEXT; \
+    ALTER TABLE application ADD COLUMN description TEXT; \
+    ALTER TABLE application ADD COLUMN publisher TEXT; \
+    ALTER TABLE application ADD COLUMN homepage TEXT; \
+    ALTER TABLE application ADD COLUMN profile TEXT NOT NULL DEFAULT ''; \
+    ALTER TABLE application ADD COLUMN installed_at INTEGER NOT NULL DEFAULT 0; \
+    ALTER TABLE application ADD COLUMN script TEXT NOT NULL DEFAULT ''",
+    ),
+    Migration::Sql(
+        "CREATE TABLE installed_path( \
+    path TEXT NOT NULL, \
+    app_id TEXT NOT NULL, \
+    kind TEXT NOT NULL, \
+    PRIMARY KEY (path, app_id) \
+    ); \
+    CREATE INDEX installed_path_app_id ON installed_path(app_id)",
+    ),
+    Migration::Code(Database::index_all_paths),
+];
+
+const METADATA_COLUMNS: &str =
+    "name, version, description, publisher, homepage, profile, installed_at, script";
+
 pub struct Database {
     connection: ConnectionThreadSafe,
 }
 
 impl Database {
-    pub fn new(connection: ConnectionThreadSafe) -> Self {
-        let create_table_sql = "CREATE TABLE IF NOT EXISTS application( \
-        id TEXT PRIMARY KEY, \
-        recorder BLOB NOT NULL \
-        )";
-        connection.execute(create_table_sql).unwrap();
-        Self { connection }
+    /// Opens the database, migrating its schema to the latest version.
+    pub fn new(connection: ConnectionThreadSafe) -> DatabaseResult<Self> {
+        let database = Self { connection };
+        database.migrate()?;
+        Ok(database)
+    }
+
+    /// Opens the database without migrating it or writing to it, for
+    /// commands that must not touch the disk. Fails with
+    /// `DatabaseErr::Outdated` if its schema is not the latest.
+    pub fn open_read_only(connection: ConnectionThreadSafe) -> DatabaseResult<Self> {
+        let database = Self { connection };
+        let version = database.schema_version()?;
+        if version > MIGRATIONS.len() {
+            return Err(DatabaseErr::TooNew {
+                version,
+                supported: MIGRATIONS.len(),
+            });
+        }
+        if version < MIGRATIONS.len() {
+            return Err(DatabaseErr::Outdated {
+                version,
+                current: MIGRATIONS.len(),
+            });
+        }
+        Ok(database)
+    }
+
+    pub fn schema_version(&self) -> DatabaseResult<usize> {
+        let mut statement = self.connection.prepare("PRAGMA user_version")?;
+        statement.next()?;
+        Ok(statement.read::<i64, usize>(0)? as usize)
+    }
+
+    /// Runs `f` inside a transaction, committing only if it succeeds.
+    fn transaction<T>(&self, f: impl FnOnce(&Self) -> DatabaseResult<T>) -> DatabaseResult<T> {
+        self.connection.execute("BEGIN IMMEDIATE")?;
+        let res = f(self);
+        match res {
+            Ok(_) => self.connection.execute("COMMIT")?,
+            Err(_) => self.connection.execute("ROLLBACK")?,
+        }
+        res
+    }
+
+    fn migrate(&self) -> DatabaseResult<()> {
+        self.transaction(Self::migrate_in_transaction)
+    }
+
+    fn migrate_in_transaction(&self) -> DatabaseResult<()> {
+        let version = self.schema_version()?;
+        if version > MIGRATIONS.len() {
+            return Err(DatabaseErr::TooNew {
+                version,
+                supported: MIGRATIONS.len(),
+            });
+        }
+        for migration in &MIGRATIONS[version..] {
+            match migration {
+                Migration::Sql(sql) => self.connection.execute(sql)?,
+                Migration::Code(f) => f(self)?,
+            }
+        }
+        self.connection
+            .execute(format!("PRAGMA user_version = {}", MIGRATIONS.len()))?;
+        Ok(())
     }
 }
 
 impl Database {
-    pub fn add_application(&self, application: application::Application) {
-        let id = application.id().to_string();
-        let recorder_binary = application.recorder().to_binary();
+    /// Adds an application along with the paths its recorder owns. It
+    /// replaces the applications of the same name, whose records are merged
+    /// into its recorder so that uninstalling it removes what every install
+    /// of it wrote.
+    ///
+    /// Fails with `DatabaseErr::PathOwned` if a file or link it recorded
+    /// already belongs to another application.
+    pub fn add_application(&self, application: &application::Application) -> DatabaseResult<()> {
+        self.transaction(|database| {
+            let owned = database.foreign_paths(
+                &application.metadata().name,
+                recorded_paths(application.recorder()),
+            )?;
+            if let Some((path, owner)) = owned.into_iter().next() {
+                return Err(DatabaseErr::PathOwned { path, owner });
+            }
+            database.replace_applications(application)
+        })
+    }
+
+    /// Adds an application like `add_application`, taking over the files and
+    /// links it recorded that belong to another application. They are dropped
+    /// from the previous owner's recorder, so uninstalling or repairing it
+    /// leaves them alone.
+    pub fn add_application_taking_over(
+        &self,
+        application: &application::Application,
+    ) -> DatabaseResult<()> {
+        self.transaction(|database| {
+            let owned = database.foreign_paths(
+                &application.metadata().name,
+                recorded_paths(application.recorder()),
+            )?;
+            let mut taken = HashMap::<Uuid, HashSet<PathBuf>>::new();
+            for (path, owner) in owned {
+                taken.entry(owner).or_default().insert(path);
+            }
+            for (owner, paths) in taken {
+                let Some(previous) = database.get_application(owner)? else {
+                    continue;
+                };
+                let mut recorder = previous.into_recorder();
+                recorder.forget(&paths);
+                database.replace_recorder(owner, &recorder)?;
+            }
+            database.replace_applications(application)
+        })
+    }
+
+    /// Inserts `application` in place of the applications with its name.
+    fn replace_applications(&self, application: &application::Application) -> DatabaseResult<()> {
+        let mut recorder = recorder::Recorder::default();
+        for id in self.find_applications(&application.metadata().name)? {
+            if let Some(previous) = self.get_application(id)? {
+                recorder.merge(previous.into_recorder());
+            }
+            self.delete_application(id)?;
+        }
+        recorder.merge(application.recorder().clone());
+        self.insert_application(application.id(), application.metadata(), &recorder)?;
+        self.index_paths(application.id(), &recorder)
+    }
+
+    fn insert_application(
+        &self,
+        id: Uuid,
+        metadata: &application::Metadata,
+        recorder: &recorder::Recorder,
+    ) -> DatabaseResult<()> {
+        let id = id.to_string();
+        let script = metadata.script.to_string_lossy();
+        let recorder_binary = recorder.to_binary()?;
+        let mut statement = self.connection.prepare(format!(
+            "INSERT INTO application (id, {}, recorder) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            METADATA_COLUMNS
+        ))?;
+        statement.bind((1, &*id))?;
+        statement.bind((2, metadata.name.as_str()))?;
+        statement.bind((3, metadata.version.as_deref()))?;
+        statement.bind((4, metadata.description.as_deref()))?;
+        statement.bind((5, metadata.publisher.as_deref()))?;
+        statement.bind((6, metadata.homepage.as_deref()))?;
+        statement.bind((7, metadata.profile.as_str()))?;
+        statement.bind((8, metadata.installed_at))?;
+        statement.bind((9, &*script))?;
+        statement.bind((10, &recorder_binary[..]))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    /// Replaces the recorder stored for an application, and the paths it
+    /// owns, returning whether the application exists.
+    pub fn update_recorder(
+        &self,
+        application_id: Uuid,
+        recorder: &recorder::Recorder,
+    ) -> DatabaseResult<bool> {
+        self.transaction(|database| database.replace_recorder(application_id, recorder))
+    }
+
+    fn replace_recorder(
+        &self,
+        application_id: Uuid,
+        recorder: &recorder::Recorder,
+    ) -> DatabaseResult<bool> {
+        let recorder_binary = recorder.to_binary()?;
         let mut statement = self
             .connection
-            .prepare("INSERT INTO application (id, recorder) VALUES (?, ?)")
-            .unwrap();
-        statement.bind((1, &*id)).unwrap();
-        statement.bind((2, &recorder_binary[..])).unwrap();
-        statement.next().unwrap();
+            .prepare("UPDATE application SET recorder = ? WHERE id = ?")?;
+        statement.bind((1, &recorder_binary[..]))?;
+        statement.bind((2, &*application_id.to_string()))?;
+        statement.next()?;
+        if self.connection.change_count() == 0 {
+            return Ok(false);
+        }
+        self.unindex_paths(application_id)?;
+        self.index_paths(application_id, recorder)?;
+        Ok(true)
+    }
+
+    pub fn remove_application(
+        &self,
+        application_id: Uuid,
+    ) -> DatabaseResult<Option<application::Application>> {
+        let Some(application) = self.get_application(application_id)? else {
+            return Ok(None);
+        };
+        self.transaction(|database| database.delete_application(application_id))?;
+        Ok(Some(application))
+    }
+
+    fn delete_application(&self, application_id: Uuid) -> DatabaseResult<()> {
+        let mut statement = self
+            .connection
+            .prepare("DELETE FROM application WHERE id = ?")?;
+        statement.bind((1, &*application_id.to_string()))?;
+        statement.next()?;
+        self.unindex_paths(application_id)
+    }
+
+    /// Lists the metadata of every installed application without decoding
+    /// their recorders.
+    pub fn list_applications(&self) -> DatabaseResult<Vec<(Uuid, application::Metadata)>> {
+        let mut statement = self.connection.prepare(format!(
+            "SELECT id, {} FROM application ORDER BY name",
+            METADATA_COLUMNS
+        ))?;
+        let mut applications = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            let id = read_id(&statement)?;
+            applications.push((id, read_metadata(&statement)?));
+        }
+        Ok(applications)
     }
 
-    pub fn remove_application(&self, application_id: Uuid) -> Option<application::Application> {
-        let application = self.get_application(application_id)?;
+    pub fn find_applications(&self, name: &str) -> DatabaseResult<Vec<Uuid>> {
         let mut statement = self
             .connection
-            .prepare("DELETE FROM application WHERE id = ?")
+            .prepare("SELECT id FROM application WHERE name = ?")?;
+        statement.bind((1, name))?;
+        let mut ids = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            ids.push(read_id(&statement)?);
+        }
+        Ok(ids)
+    }
+
+    pub fn get_application(
+        &self,
+        application_id: Uuid,
+    ) -> DatabaseResult<Option<application::Application>> {
+        let mut statement = self.connection.prepare(format!(
+            "SELECT {}, recorder FROM application WHERE id = ?",
+            METADATA_COLUMNS
+        ))?;
+        statement.bind((1, &*application_id.to_string()))?;
+        if let sqlite::State::Row = statement.next()? {
+            let metadata = read_metadata(&statement)?;
+            let recorder_data = statement.read::<Vec<u8>, &str>("recorder")?;
+            let recorder = recorder::Recorder::from_binary(&recorder_data)?;
+            Ok(Some(application::Application::new(
+                application_id,
+                metadata,
+                recorder,
+            )))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathKind {
+    Directory,
+    File,
+    Link,
+}
+
+impl PathKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PathKind::Directory => "directory",
+            PathKind::File => "file",
+            PathKind::Link => "link",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "directory" => Some(PathKind::Directory),
+            "file" => Some(PathKind::File),
+            "link" => Some(PathKind::Link),
+            _ => None,
+        }
+    }
+}
+
+impl Display for PathKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Database {
+    /// Lists the applications that recorded `path`, and as what.
+    ///
+    /// Several applications can own the same directory, while a file or link
+    /// normally has a single owner.
+    pub fn owners(&self, path: &Path) -> DatabaseResult<Vec<(Uuid, PathKind)>> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT app_id, kind FROM installed_path WHERE path = ? ORDER BY app_id")?;
+        statement.bind((1, &*path.to_string_lossy()))?;
+        let mut owners = Vec::new();
+        while let sqlite::State::Row = statement.next()? {
+            let id = statement.read::<String, &str>("app_id")?;
+            let id = Uuid::parse_str(&id).map_err(|_| DatabaseErr::InvalidId(id))?;
+            let kind = statement.read::<String, &str>("kind")?;
+            let kind = PathKind::parse(&kind).ok_or(DatabaseErr::InvalidPathKind(kind))?;
+            owners.push((id, kind));
+        }
+        Ok(owners)
+    }
+
+    /// The files and links among `paths` that already belong to an
+    /// application, with their owner. Directories can be shared and are not
+    /// included.
+    pub fn owned_paths<'a>(
+        &self,
+        paths: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> DatabaseResult<HashMap<PathBuf, Uuid>> {
+        let mut owned = HashMap::new();
+        for path in paths {
+            for (owner, kind) in self.owners(path)? {
+                if kind != PathKind::Directory {
+                    owned.insert(path.clone(), owner);
+                }
+            }
+        }
+        Ok(owned)
+    }
+
+    /// The paths among `paths` that `owned_paths` finds, leaving out those of
+    /// the applications named `name`, which installing it again replaces.
+    pub fn foreign_paths<'a>(
+        &self,
+        name: &str,
+        paths: impl IntoIterator<Item = &'a PathBuf>,
+    ) -> DatabaseResult<HashMap<PathBuf, Uuid>> {
+        let previous: HashSet<Uuid> = self.find_applications(name)?.into_iter().collect();
+        let mut owned = self.owned_paths(paths)?;
+        owned.retain(|_, owner| !previous.contains(owner));
+        Ok(owned)
+    }
+
+    /// The files and links of an application that another application also
+    /// owns. Uninstalling it must leave them in place.
+    pub fn shared_paths(&self, application_id: Uuid) -> DatabaseResult<HashSet<PathBuf>> {
+        let mut statement = self.connection.prepare(
+            "SELECT DISTINCT mine.path FROM installed_path mine \
+            JOIN installed_path other ON other.path = mine.path AND other.app_id != mine.app_id \
+            WHERE mine.app_id = ? AND mine.kind != 'directory' AND other.kind != 'directory'",
+        )?;
+        statement.bind((1, &*application_id.to_string()))?;
+        let mut paths = HashSet::new();
+        while let sqlite::State::Row = statement.next()? {
+            paths.insert(PathBuf::from(statement.read::<String, &str>("path")?));
+        }
+        Ok(paths)
+    }
+
+    fn index_paths(
+        &self,
+        application_id: Uuid,
+        recorder: &recorder::Recorder,
+    ) -> DatabaseResult<()> {
+        let id = application_id.to_string();
+        let mut statement = self.connection.prepare(
+            "INSERT OR IGNORE INTO installed_path (path, app_id, kind) VALUES (?, ?, ?)",
+        )?;
+        let paths = recorder
+            .directories()
+            .iter()
+            .map(|r| (r.path(), PathKind::Directory))
+            .chain(recorder.files().iter().map(|r| (r.path(), PathKind::File)))
+            .chain(recorder.links().iter().map(|r| (r.path(), PathKind::Link)));
+        for (path, kind) in paths {
+            statement.reset()?;
+            statement.bind((1, &*path.to_string_lossy()))?;
+            statement.bind((2, id.as_str()))?;
+            statement.bind((3, kind.as_str()))?;
+            statement.next()?;
+        }
+        Ok(())
+    }
+
+    fn unindex_paths(&self, application_id: Uuid) -> DatabaseResult<()> {
+        let mut statement = self
+            .connection
+            .prepare("DELETE FROM installed_path WHERE app_id = ?")?;
+        statement.bind((1, &*application_id.to_string()))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    /// Fills `installed_path` for applications installed before it existed.
+    fn index_all_paths(&self) -> DatabaseResult<()> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, recorder FROM application")?;
+        while let sqlite::State::Row = statement.next()? {
+            let id = read_id(&statement)?;
+            let recorder_data = statement.read::<Vec<u8>, &str>("recorder")?;
+            let recorder = recorder::Recorder::from_binary(&recorder_data)?;
+            self.index_paths(id, &recorder)?;
+        }
+        Ok(())
+    }
+}
+
+fn recorded_paths(recorder: &recorder::Recorder) -> impl Iterator<Item = &PathBuf> {
+    recorder
+        .files()
+        .iter()
+        .map(|r| r.path())
+        .chain(recorder.links().iter().map(|r| r.path()))
+}
+
+fn read_id(statement: &Statement) -> DatabaseResult<Uuid> {
+    let id = statement.read::<String, &str>("id")?;
+    Uuid::parse_str(&id).map_err(|_| DatabaseErr::InvalidId(id))
+}
+
+fn read_metadata(statement: &Statement) -> DatabaseResult<application::Metadata> {
+    Ok(application::Metadata {
+        name: statement.read::<String, &str>("name")?,
+        version: statement.read::<Option<String>, &str>("version")?,
+        description: statement.read::<Option<String>, &str>("description")?,
+        publisher: statement.read::<Option<String>, &str>("publisher")?,
+        homepage: statement.read::<Option<String>, &str>("homepage")?,
+        profile: statement.read::<String, &str>("profile")?,
+        installed_at: statement.read::<i64, &str>("installed_at")?,
+        script: PathBuf::from(statement.read::<String, &str>("script")?),
+    })
+}
+
+#[derive(Debug)]
+pub enum DatabaseErr {
+    Sqlite(sqlite::Error),
+    Recorder(recorder::FormatErr),
+    InvalidId(String),
+    InvalidPathKind(String),
+    /// A file or link is already recorded by another application.
+    PathOwned {
+        path: PathBuf,
+        owner: Uuid,
+    },
+    /// The database was written by a newer version of this program.
+    TooNew {
+        version: usize,
+        supported: usize,
+    },
+    /// The database needs migrating, which a read-only database cannot do.
+    Outdated {
+        version: usize,
+        current: usize,
+    },
+}
+
+impl From<sqlite::Error> for DatabaseErr {
+    fn from(error: sqlite::Error) -> Self {
+        DatabaseErr::Sqlite(error)
+    }
+}
+
+impl From<recorder::FormatErr> for DatabaseErr {
+    fn from(error: recorder::FormatErr) -> Self {
+        DatabaseErr::Recorder(error)
+    }
+}
+
+impl Display for DatabaseErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+impl std::error::Error for DatabaseErr {}
+
+pub type DatabaseResult<T> = Result<T, DatabaseErr>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> Database {
+        let connection = sqlite::Connection::open_thread_safe(":memory:").unwrap();
+        Database::new(connection).unwrap()
+    }
+
+    fn application(name: &str, files: &[&str]) -> application::Application {
+        let mut recorder = recorder::Recorder::default();
+        recorder.record_directory(recorder::DirectoryRecord::from(PathBuf::from("/shared")));
+        for file in files {
+            recorder.record_file(recorder::FileRecord::from(PathBuf::from(file)));
+        }
+        let metadata = application::Metadata {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        application::Application::new(Uuid::new_v4(), metadata, recorder)
+    }
+
+    #[test]
+    fn baseline_databases_are_migrated() {
+        // The schema and recorder encoding of the first release, which had
+        // no `user_version`.
+        let connection = sqlite::Connection::open_thread_safe(":memory:").unwrap();
+        connection
+            .execute("CREATE TABLE IF NOT EXISTS application(id TEXT PRIMARY KEY, recorder BLOB NOT NULL)")
             .unwrap();
-        statement.bind((1, &*application_id.to_string())).unwrap();
+        let recorder =
+            std::fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/recorder/v0.bin"))
+                .unwrap();
+        let id = Uuid::new_v4();
+        let mut statement = connection
+            .prepare("INSERT INTO application (id, recorder) VALUES (?, ?)")
+            .unwrap();
+        statement.bind((1, &*id.to_string())).unwrap();
+        statement.bind((2, &recorder[..])).unwrap();
         statement.next().unwrap();
-        Some(application)
+        drop(statement);
+
+        let database = Database::new(connection).unwrap();
+        assert_eq!(database.schema_version().unwrap(), MIGRATIONS.len());
+        let application = database.get_application(id).unwrap().unwrap();
+        assert_eq!(application.metadata().name, "");
+        let files: Vec<_> = application
+            .recorder()
+            .files()
+            .iter()
+            .map(|r| r.path())
+            .collect();
+        assert_eq!(
+            files,
+            vec![Path::new("/opt/demo/a"), Path::new("/opt/demo/b")]
+        );
+        // The paths of existing recorders are indexed by the last migration.
+        let owners = database.owners(Path::new("/opt/demo/a")).unwrap();
+        assert_eq!(owners, vec![(id, PathKind::File)]);
+        let owners = database.owners(Path::new("/usr/local/bin/demo")).unwrap();
+        assert_eq!(owners, vec![(id, PathKind::Link)]);
+        // Opening again finds nothing left to migrate.
+        assert_eq!(database.list_applications().unwrap().len(), 1);
+        database.migrate().unwrap();
+        assert_eq!(database.schema_version().unwrap(), MIGRATIONS.len());
     }
 
-    pub fn get_application(&self, application_id: Uuid) -> Option<application::Application> {
-        let mut statement = self
+    #[test]
+    fn metadata_columns_are_added_to_existing_rows() {
+        // A database at `user_version` 1, before the metadata columns.
+        let connection = sqlite::Connection::open_thread_safe(":memory:").unwrap();
+        connection
+            .execute("CREATE TABLE application(id TEXT PRIMARY KEY, recorder BLOB NOT NULL)")
+            .unwrap();
+        connection.execute("PRAGMA user_version = 1").unwrap();
+        let mut recorder = recorder::Recorder::default();
+        recorder.record_file(recorder::FileRecord::from(PathBuf::from("/opt/demo/a")));
+        let id = Uuid::new_v4();
+        let mut statement = connection
+            .prepare("INSERT INTO application (id, recorder) VALUES (?, ?)")
+            .unwrap();
+        statement.bind((1, &*id.to_string())).unwrap();
+        statement
+            .bind((2, &recorder.to_binary().unwrap()[..]))
+            .unwrap();
+        statement.next().unwrap();
+        drop(statement);
+
+        let database = Database::new(connection).unwrap();
+        let mut statement = database
             .connection
-            .prepare("SELECT recorder FROM application WHERE id = ?")
+            .prepare("SELECT name FROM pragma_table_info('application')")
             .unwrap();
-        statement.bind((1, &*application_id.to_string())).unwrap();
-        if let Ok(sqlite::State::Row) = statement.next() {
-            let recorder_data = statement.read::<Vec<u8>, &str>("recorder").unwrap();
-            let recorder = recorder::Recorder::from_binary(&recorder_data);
-            Some(application::Application::new(application_id, recorder))
-        } else {
-            None
+        let mut columns = Vec::new();
+        while let sqlite::State::Row = statement.next().unwrap() {
+            columns.push(statement.read::<String, usize>(0).unwrap());
+        }
+        for column in METADATA_COLUMNS.split(", ") {
+            assert!(columns.iter().any(|c| c == column), "{} is missing", column);
+        }
+        let application = database.get_application(id).unwrap().unwrap();
+        let metadata = application.metadata();
+        assert_eq!(metadata.name, "");
+        assert_eq!(metadata.version, None);
+        assert_eq!(metadata.profile, "");
+        assert_eq!(metadata.installed_at, 0);
+        assert_eq!(metadata.script, PathBuf::new());
+        let files = application.recorder().files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path(), Path::new("/opt/demo/a"));
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let database = open();
+        let metadata = application::Metadata {
+            name: "demo".to_string(),
+            version: Some("1.2.3".to_string()),
+            description: Some("A demo".to_string()),
+            publisher: Some("Demo Inc.".to_string()),
+            homepage: Some("https://example.com/demo".to_string()),
+            profile: "global".to_string(),
+            installed_at: 1_700_000_000,
+            script: PathBuf::from("/scripts/demo.rhai"),
+        };
+        let id = Uuid::new_v4();
+        let application =
+            application::Application::new(id, metadata.clone(), recorder::Recorder::default());
+        database.add_application(&application).unwrap();
+
+        let expected = serde_json::to_value(&metadata).unwrap();
+        let read = database.get_application(id).unwrap().unwrap();
+        assert_eq!(serde_json::to_value(read.metadata()).unwrap(), expected);
+        let listed = database.list_applications().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, id);
+        assert_eq!(serde_json::to_value(&listed[0].1).unwrap(), expected);
+    }
+
+    #[test]
+    fn read_only_requires_a_migrated_schema() {
+        let connection = sqlite::Connection::open_thread_safe(":memory:").unwrap();
+        let err = Database::open_read_only(connection).err().unwrap();
+        assert!(matches!(err, DatabaseErr::Outdated { version: 0, .. }));
+    }
+
+    #[test]
+    fn owned_files_are_refused() {
+        let database = open();
+        let a = application("a", &["/shared/a", "/shared/common"]);
+        database.add_application(&a).unwrap();
+        let b = application("b", &["/shared/b", "/shared/common"]);
+        let err = database.add_application(&b).unwrap_err();
+        assert!(matches!(
+            err,
+            DatabaseErr::PathOwned { ref path, owner } if path == Path::new("/shared/common") && owner == a.id()
+        ));
+        assert!(database.get_application(b.id()).unwrap().is_none());
+        // Directories are shared freely.
+        let c = application("c", &["/shared/c"]);
+        database.add_application(&c).unwrap();
+    }
+
+    #[test]
+    fn reinstalling_replaces_the_previous_install() {
+        let dir = std::env::temp_dir().join(format!(
+            "veridian-database-reinstall-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let env_file = dir.join("env.sh");
+        let target = bundle_deploy::env::EnvTarget::new(
+            env_file.clone(),
+            bundle_deploy::env::EnvFileFormat::Shell,
+        );
+        let installer = || {
+            let metadata = application::Metadata {
+                name: "demo".to_string(),
+                ..Default::default()
+            };
+            crate::installer::Installer::new(
+                metadata,
+                vec![crate::installer::CreateDirectoryTask::new(dir.join("demo"))],
+                vec![crate::installer::WriteFileTask::Contents {
+                    content: b"demo".to_vec(),
+                    to: dir.join("demo/file"),
+                    conflict: None,
+                    attributes: Default::default(),
+                }],
+                Vec::new(),
+                vec![crate::installer::EnvTask::new(
+                    target.clone(),
+                    bundle_deploy::env::EnvChange::PrependPath(dir.join("demo")),
+                )],
+            )
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let database = open();
+        for _ in 0..2 {
+            let mut installer = installer();
+            let owned = database
+                .foreign_paths("demo", installer.destinations())
+                .unwrap();
+            installer.set_owned_paths(owned);
+            let application = runtime
+                .block_on(installer.install())
+                .map_err(|(_, e)| e)
+                .unwrap();
+            database.add_application(&application).unwrap();
         }
+
+        let ids = database.find_applications("demo").unwrap();
+        assert_eq!(ids.len(), 1);
+        let owners = database.owners(&dir.join("demo/file")).unwrap();
+        assert_eq!(owners, vec![(ids[0], PathKind::File)]);
+        let application = database.remove_application(ids[0]).unwrap().unwrap();
+        runtime
+            .block_on(application.into_recorder().rollback())
+            .unwrap();
+        assert!(!dir.join("demo").exists());
+        // Both installs added the line, and the env file is gone with them.
+        assert!(!env_file.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn taking_over_transfers_ownership() {
+        let database = open();
+        let a = application("a", &["/shared/a", "/shared/common"]);
+        database.add_application(&a).unwrap();
+        let b = application("b", &["/shared/common"]);
+        database.add_application_taking_over(&b).unwrap();
+
+        let owners = database.owners(Path::new("/shared/common")).unwrap();
+        assert_eq!(owners, vec![(b.id(), PathKind::File)]);
+        let a = database.get_application(a.id()).unwrap().unwrap();
+        let files: Vec<_> = a.recorder().files().iter().map(|r| r.path()).collect();
+        assert_eq!(files, vec![Path::new("/shared/a")]);
+        assert!(database.shared_paths(a.id()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn shared_paths_lists_files_with_another_owner() {
+        let database = open();
+        let a = application("a", &["/shared/a", "/shared/common"]);
+        let b = application("b", &["/shared/b"]);
+        database.add_application(&a).unwrap();
+        database.add_application(&b).unwrap();
+        // A second owner as written by `install --force` before ownership
+        // was transferred.
+        database
+            .connection
+            .execute(format!(
+                "INSERT INTO installed_path VALUES ('/shared/common', '{}', 'file')",
+                b.id()
+            ))
+            .unwrap();
+
+        let shared = database.shared_paths(a.id()).unwrap();
+        assert_eq!(shared, HashSet::from([PathBuf::from("/shared/common")]));
     }
 }
@@ -1,34 +1,109 @@
-use std::fs;
-use std::path::PathBuf;
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 
+static ROOT: OnceLock<Option<PathBuf>> = OnceLock::new();
 static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
 static DATA_PATH: OnceLock<PathBuf> = OnceLock::new();
 
+/// A file next to the executable that turns on portable mode, in which the
+/// configuration and the databases are kept in that directory.
+pub const PORTABLE_MARKER: &str = "veridian-manager.portable";
+
+/// Keeps the configuration in `root/config` and the data in `root/data`
+/// instead of the user's directories. Must be called before anything else in
+/// this module; once the directories are chosen they do not change and
+/// `root` is handed back.
+pub fn set_root(root: PathBuf) -> Result<(), PathBuf> {
+    let root = std::path::absolute(&root).unwrap_or(root);
+    ROOT.set(Some(root))
+        .map_err(|root| root.unwrap_or_default())
+}
+
+/// The directory that replaces the user's directories, if any. Unless set
+/// with [`set_root`], it is `VERIDIAN_HOME` or, in portable mode, the
+/// directory of the executable.
+pub fn root() -> Option<&'static Path> {
+    ROOT.get_or_init(|| root_from(env::var_os("VERIDIAN_HOME"), env::current_exe().ok()))
+        .as_deref()
+}
+
+/// The root chosen by `home`, the value of `VERIDIAN_HOME`, or else by a
+/// portable marker next to `exe`.
+fn root_from(home: Option<OsString>, exe: Option<PathBuf>) -> Option<PathBuf> {
+    if let Some(home) = home.filter(|h| !h.is_empty()) {
+        let home = PathBuf::from(home);
+        return Some(std::path::absolute(&home).unwrap_or(home));
+    }
+    let exe = exe?;
+    let dir = exe.parent()?;
+    dir.join(PORTABLE_MARKER)
+        .exists()
+        .then(|| dir.to_path_buf())
+}
+
+/// The directory of the user configuration. It is not created.
 pub fn config() -> &'static PathBuf {
     CONFIG_PATH.get_or_init(|| {
-        let path = if let Some(p) =
+        if let Some(root) = root() {
+            root.join("config")
+        } else if let Some(p) =
             directories::ProjectDirs::from("top.equaltrue", "", "Veridian Manager")
         {
             p.config_local_dir().to_path_buf()
         } else {
             PathBuf::from("/etc/veridian-manager/config")
-        };
-        fs::create_dir_all(&path).unwrap();
-        path
+        }
     })
 }
 
+/// The directory of the databases. It is not created.
 pub fn data() -> &'static PathBuf {
     DATA_PATH.get_or_init(|| {
-        let path = if let Some(p) =
+        if let Some(root) = root() {
+            root.join("data")
+        } else if let Some(p) =
             directories::ProjectDirs::from("top.equaltrue", "", "Veridian Manager")
         {
             p.data_local_dir().to_path_buf()
         } else {
             PathBuf::from("/etc/veridian-manager/data")
-        };
-        fs::create_dir_all(&path).unwrap();
-        path
+        }
     })
 }
+
+/// The file name, in [`data`], of the database of the applications installed
+/// into the profile `profile`.
+pub fn database_file_name(profile: &str) -> String {
+    format!("database-{}.sqlite", profile)
+}
+
+/// The file name, in [`data`], of the database that all profiles shared
+/// before each got its own.
+pub const LEGACY_DATABASE_FILE_NAME: &str = "database.sqlite";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn root_is_chosen_by_home_then_by_portable_marker() {
+        let dir = env::temp_dir().join(format!("veridian-dir-path-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("veridian-manager");
+
+        assert_eq!(root_from(None, Some(exe.clone())), None);
+        assert_eq!(root_from(Some(OsString::new()), Some(exe.clone())), None);
+        std::fs::write(dir.join(PORTABLE_MARKER), "").unwrap();
+        assert_eq!(root_from(None, Some(exe.clone())), Some(dir.clone()));
+        assert_eq!(
+            root_from(Some(OsString::new()), Some(exe.clone())),
+            Some(dir.clone())
+        );
+        let home = root_from(Some(OsString::from("home")), Some(exe)).unwrap();
+        assert_eq!(home, env::current_dir().unwrap().join("home"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
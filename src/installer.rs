@@ -1,43 +1,244 @@
-use crate::{application, recorder};
-use std::path::PathBuf;
+use crate::progress::{Phase, Progress, ProgressEvent};
+use crate::{application, preflight, progress, recorder};
+use bundle_deploy::file_system::{Attributes, WriteError};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use uuid::Uuid;
 
 #[derive(Debug)]
-pub struct CreateDirectoryTask(PathBuf);
+pub struct CreateDirectoryTask {
+    path: PathBuf,
+    attributes: Attributes,
+}
 
 impl CreateDirectoryTask {
     pub fn new(path: PathBuf) -> Self {
-        CreateDirectoryTask(path)
+        Self {
+            path,
+            attributes: Attributes::default(),
+        }
     }
 
     pub fn path(&self) -> &PathBuf {
-        &self.0
+        &self.path
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+
+    pub fn attributes_mut(&mut self) -> &mut Attributes {
+        &mut self.attributes
     }
 }
 
-#[derive(Debug)]
+/// What to do when the destination of a file or link already exists.
+///
+/// The default is `Overwrite`. Files of other installed applications are
+/// protected by ownership instead, see `Installer::set_owned_paths`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    Fail,
+    /// Keep the existing file and drop the task.
+    Skip,
+    /// Move the existing file aside, to be restored on rollback.
+    Backup,
+    /// Move the existing file aside like `Backup`, and delete it once the
+    /// install has succeeded. A failed install still puts it back.
+    #[default]
+    Overwrite,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ConflictPolicy::Fail),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "backup" => Ok(ConflictPolicy::Backup),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            _ => Err(format!(
+                "unknown conflict policy `{}`, expected fail, skip, backup or overwrite",
+                s
+            )),
+        }
+    }
+}
+
+/// `conflict` overrides the installer's conflict policy for this task, and
+/// `attributes` are applied to the file once it is written.
+#[derive(Debug, Clone)]
 pub enum WriteFileTask {
-    FromPath { from: PathBuf, to: PathBuf },
-    Contents { content: Vec<u8>, to: PathBuf },
+    FromPath {
+        from: PathBuf,
+        to: PathBuf,
+        conflict: Option<ConflictPolicy>,
+        attributes: Attributes,
+    },
+    Contents {
+        content: Vec<u8>,
+        to: PathBuf,
+        conflict: Option<ConflictPolicy>,
+        attributes: Attributes,
+    },
+    /// `entry` is the normalized path of the file inside `archive`.
+    FromArchive {
+        archive: PathBuf,
+        entry: PathBuf,
+        size: u64,
+        to: PathBuf,
+        conflict: Option<ConflictPolicy>,
+        attributes: Attributes,
+    },
 }
 
-#[derive(Debug)]
+impl WriteFileTask {
+    pub fn conflict(&self) -> Option<ConflictPolicy> {
+        match self {
+            WriteFileTask::FromPath { conflict, .. }
+            | WriteFileTask::Contents { conflict, .. }
+            | WriteFileTask::FromArchive { conflict, .. } => *conflict,
+        }
+    }
+
+    pub fn set_conflict(&mut self, policy: Option<ConflictPolicy>) {
+        match self {
+            WriteFileTask::FromPath { conflict, .. }
+            | WriteFileTask::Contents { conflict, .. }
+            | WriteFileTask::FromArchive { conflict, .. } => *conflict = policy,
+        }
+    }
+
+    /// Number of bytes the task writes, `None` when its source is unreadable.
+    pub async fn size(&self) -> Option<u64> {
+        match self {
+            WriteFileTask::FromPath { from, .. } => bundle_deploy::file_system::metadata(from)
+                .await
+                .ok()
+                .map(|m| m.len()),
+            WriteFileTask::Contents { content, .. } => Some(content.len() as u64),
+            WriteFileTask::FromArchive { size, .. } => Some(*size),
+        }
+    }
+
+    pub fn attributes(&self) -> &Attributes {
+        match self {
+            WriteFileTask::FromPath { attributes, .. }
+            | WriteFileTask::Contents { attributes, .. }
+            | WriteFileTask::FromArchive { attributes, .. } => attributes,
+        }
+    }
+
+    pub fn attributes_mut(&mut self) -> &mut Attributes {
+        match self {
+            WriteFileTask::FromPath { attributes, .. }
+            | WriteFileTask::Contents { attributes, .. }
+            | WriteFileTask::FromArchive { attributes, .. } => attributes,
+        }
+    }
+
+    pub fn to(&self) -> &PathBuf {
+        match self {
+            WriteFileTask::FromPath { to, .. }
+            | WriteFileTask::Contents { to, .. }
+            | WriteFileTask::FromArchive { to, .. } => to,
+        }
+    }
+
+    pub fn to_mut(&mut self) -> &mut PathBuf {
+        match self {
+            WriteFileTask::FromPath { to, .. }
+            | WriteFileTask::Contents { to, .. }
+            | WriteFileTask::FromArchive { to, .. } => to,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum LinkType {
     Shortcut,
     Symbolic,
     Hard,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CreateLinkTask {
     from: PathBuf,
     to: PathBuf,
     link_type: LinkType,
+    conflict: Option<ConflictPolicy>,
 }
 
-#[derive(Debug)]
-pub struct EnvTask {}
+impl CreateLinkTask {
+    pub fn new(from: PathBuf, to: PathBuf, link_type: LinkType) -> Self {
+        Self {
+            from,
+            to,
+            link_type,
+            conflict: None,
+        }
+    }
+
+    pub fn from(&self) -> &PathBuf {
+        &self.from
+    }
+
+    pub fn to(&self) -> &PathBuf {
+        &self.to
+    }
+
+    pub fn link_type(&self) -> &LinkType {
+        &self.link_type
+    }
+
+    pub fn conflict(&self) -> Option<ConflictPolicy> {
+        self.conflict
+    }
+
+    /// Overrides the installer's conflict policy for this link.
+    pub fn set_conflict(&mut self, policy: Option<ConflictPolicy>) {
+        self.conflict = policy;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EnvTask {
+    target: bundle_deploy::env::EnvTarget,
+    change: bundle_deploy::env::EnvChange,
+}
+
+impl EnvTask {
+    pub fn new(
+        target: bundle_deploy::env::EnvTarget,
+        change: bundle_deploy::env::EnvChange,
+    ) -> Self {
+        Self { target, change }
+    }
+
+    pub fn target(&self) -> &bundle_deploy::env::EnvTarget {
+        &self.target
+    }
+
+    pub fn change(&self) -> &bundle_deploy::env::EnvChange {
+        &self.change
+    }
+}
 
 pub struct Installer {
+    metadata: application::Metadata,
+    concurrency: usize,
+    progress: Arc<dyn Progress>,
+    conflict: ConflictPolicy,
+    owned: HashMap<PathBuf, Uuid>,
     dir_tasks: Vec<CreateDirectoryTask>,
     file_tasks: Vec<WriteFileTask>,
     link_tasks: Vec<CreateLinkTask>,
@@ -46,12 +247,18 @@ pub struct Installer {
 
 impl Installer {
     pub fn new(
+        metadata: application::Metadata,
         dir_tasks: Vec<CreateDirectoryTask>,
         file_tasks: Vec<WriteFileTask>,
         link_tasks: Vec<CreateLinkTask>,
         env_tasks: Vec<EnvTask>,
     ) -> Self {
         Self {
+            metadata,
+            concurrency: thread::available_parallelism().map_or(4, |n| n.get()),
+            progress: Arc::new(progress::Silent),
+            conflict: ConflictPolicy::default(),
+            owned: HashMap::new(),
             dir_tasks,
             file_tasks,
             link_tasks,
@@ -59,47 +266,990 @@ impl Installer {
         }
     }
 
-    pub async fn install(self) -> InstallResult {
+    pub fn metadata(&self) -> &application::Metadata {
+        &self.metadata
+    }
+
+    /// Sets how many files are written at the same time.
+    pub fn set_concurrency(&mut self, concurrency: usize) {
+        self.concurrency = concurrency.max(1);
+    }
+
+    pub fn conflict_policy(&self) -> ConflictPolicy {
+        self.conflict
+    }
+
+    /// Sets the conflict policy of every task that does not have its own.
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict = policy;
+    }
+
+    /// Sets the paths that belong to other applications, with their owner,
+    /// usually from `Database::foreign_paths`. Installing fails with
+    /// `InstallErr::Owned` before anything is written if a file or link
+    /// destination is among them.
+    pub fn set_owned_paths(&mut self, owned: HashMap<PathBuf, Uuid>) {
+        self.owned = owned;
+    }
+
+    pub fn set_progress(&mut self, progress: Arc<dyn Progress>) {
+        self.progress = progress;
+    }
+
+    pub fn dir_tasks(&self) -> &[CreateDirectoryTask] {
+        &self.dir_tasks
+    }
+
+    pub fn file_tasks(&self) -> &[WriteFileTask] {
+        &self.file_tasks
+    }
+
+    pub fn link_tasks(&self) -> &[CreateLinkTask] {
+        &self.link_tasks
+    }
+
+    pub fn env_tasks(&self) -> &[EnvTask] {
+        &self.env_tasks
+    }
+
+    /// Every file and link destination, the paths an install overwrites.
+    pub fn destinations(&self) -> impl Iterator<Item = &PathBuf> {
+        self.file_tasks
+            .iter()
+            .map(|t| t.to())
+            .chain(self.link_tasks.iter().map(|t| t.to()))
+    }
+
+    pub async fn install(mut self) -> InstallResult {
         let mut recorder = recorder::Recorder::with_capacity(
             self.dir_tasks.len(),
             self.file_tasks.len(),
             self.link_tasks.len(),
             self.env_tasks.len(),
         );
+        let metadata = self.metadata.clone();
+        let progress = self.progress.clone();
+        let res = async {
+            self.check_owned()?;
+            self.preflight(Vec::new()).await?;
+            let overwritten = self.resolve_conflicts(&mut recorder).await?;
+            self.apply(&mut recorder).await?;
+            Ok(overwritten)
+        }
+        .await;
+        match res {
+            Ok(overwritten) => {
+                discard_overwritten(&mut recorder, &overwritten).await;
+                Ok(finish(metadata, recorder))
+            }
+            Err(e) => {
+                progress.event(&ProgressEvent::Error(&e));
+                Err((recorder, e))
+            }
+        }
+    }
+
+    /// Installs through a staging directory created under `root`.
+    ///
+    /// Everything destined for `root` is first written into the staging
+    /// directory, checked, and then renamed into place. Links and env changes
+    /// outside `root` are only applied once that swap has succeeded, so a
+    /// failure or crash before it leaves the final destinations untouched,
+    /// apart from conflicting files that were backed up or removed up front.
+    pub async fn install_staged(mut self, root: &Path) -> InstallResult {
+        let staging = root.join(format!(".veridian-staging-{}", Uuid::new_v4()));
+        let metadata = self.metadata.clone();
+        let progress = self.progress.clone();
+        let mut recorder = recorder::Recorder::default();
+        let res = async {
+            self.check_owned()?;
+            self.preflight(vec![root.to_path_buf()]).await?;
+            self.resolve_conflicts(&mut recorder).await
+        }
+        .await;
+        let overwritten = match res {
+            Ok(overwritten) => overwritten,
+            Err(e) => {
+                progress.event(&ProgressEvent::Error(&e));
+                return Err((recorder, e));
+            }
+        };
+        let (staged, after) = self.split_staged(root, &staging);
+        let mut staged_recorder = recorder::Recorder::default();
+        let res = async {
+            create_directory(root, &Attributes::default(), &mut recorder).await?;
+            bundle_deploy::file_system::create_dir(&staging)
+                .await
+                .map_err(|source| InstallErr::CreateDirectory {
+                    path: staging.clone(),
+                    source,
+                })?;
+            staged.apply(&mut staged_recorder).await?;
+            verify_staged(&staged_recorder).await?;
+            // Every directory in staging was created there, but only those
+            // missing from `root` are created by the swap.
+            let mut existing = HashSet::new();
+            for record in staged_recorder.directories() {
+                if exists(&unstage(record.path().clone(), &staging, root)).await {
+                    existing.insert(record.path().clone());
+                }
+            }
+            staged_recorder.forget(&existing);
+            Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            progress.event(&ProgressEvent::Error(&e));
+            let _ = staged_recorder.rollback().await;
+            let _ = bundle_deploy::file_system::remove_dir_all(&staging).await;
+            return Err((recorder, e));
+        }
+        progress.event(&ProgressEvent::Phase(Phase::Swapping));
+        if let Err(e) = swap(&staging, root).await {
+            progress.event(&ProgressEvent::Error(&e));
+            // Only what was moved out of staging is ours to roll back in `root`.
+            let mut unmoved = HashSet::new();
+            let staged_paths = staged_recorder
+                .files()
+                .iter()
+                .map(|r| r.path())
+                .chain(staged_recorder.links().iter().map(|r| r.path()));
+            for path in staged_paths {
+                if exists(path).await {
+                    unmoved.insert(path.clone());
+                }
+            }
+            staged_recorder.forget(&unmoved);
+            recorder.merge(staged_recorder.map_paths(|path| unstage(path, &staging, root)));
+            let _ = bundle_deploy::file_system::remove_dir_all(&staging).await;
+            return Err((recorder, e));
+        }
+        recorder.merge(staged_recorder.map_paths(|path| unstage(path, &staging, root)));
+        let _ = bundle_deploy::file_system::remove_dir(&staging).await;
+        match after.apply(&mut recorder).await {
+            Ok(_) => {
+                discard_overwritten(&mut recorder, &overwritten).await;
+                Ok(finish(metadata, recorder))
+            }
+            Err(e) => {
+                progress.event(&ProgressEvent::Error(&e));
+                Err((recorder, e))
+            }
+        }
+    }
+
+    fn check_owned(&self) -> Result<(), InstallErr> {
+        let mut owned: Vec<_> = self
+            .destinations()
+            .filter_map(|path| Some((path.clone(), *self.owned.get(path)?)))
+            .collect();
+        if owned.is_empty() {
+            return Ok(());
+        }
+        owned.sort();
+        Err(InstallErr::Owned(owned))
+    }
+
+    /// Re-applies the tasks whose results no longer match `recorder`: files
+    /// and directories that are missing or differ from their recorded state,
+    /// and links that are missing. Env changes are left alone.
+    ///
+    /// Everything rewritten is merged into `recorder`, also when an error
+    /// stops the repair part way.
+    pub async fn repair(self, recorder: &mut recorder::Recorder) -> Result<Repaired, InstallErr> {
+        let mut damaged: HashSet<PathBuf> = recorder
+            .verify()
+            .await
+            .into_iter()
+            .map(|issue| issue.path().clone())
+            .collect();
+        let mut repair = Installer::new(
+            self.metadata,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+        repair.concurrency = self.concurrency;
+        repair.progress = self.progress;
         for task in self.dir_tasks {
-            let res = bundle_deploy::file_system::create_dir_all(task.path()).await;
+            // Creating an existing directory only gives it its attributes back.
+            if damaged.remove(task.path()) || !exists(task.path()).await {
+                repair.dir_tasks.push(task);
+            }
+        }
+        for task in self.file_tasks {
+            if damaged.remove(task.to()) {
+                repair.file_tasks.push(task);
+            }
+        }
+        for task in self.link_tasks {
+            if !exists(task.to()).await {
+                repair.link_tasks.push(task);
+            }
+        }
+        repair.preflight(Vec::new()).await?;
+        for task in &repair.file_tasks {
+            // Removed first so that the rewritten file gets its mode back too.
+            if let Err(source) = bundle_deploy::file_system::remove_file(task.to()).await
+                && source.kind() != io::ErrorKind::NotFound
+            {
+                return Err(InstallErr::WriteFile {
+                    from: None,
+                    to: task.to().clone(),
+                    source,
+                });
+            }
+        }
+        let repaired = Repaired {
+            directories: repair.dir_tasks.iter().map(|t| t.path().clone()).collect(),
+            files: repair.file_tasks.iter().map(|t| t.to().clone()).collect(),
+            links: repair.link_tasks.iter().map(|t| t.to().clone()).collect(),
+            unrepairable: damaged.into_iter().collect(),
+        };
+        let mut written = recorder::Recorder::default();
+        let res = repair.apply(&mut written).await;
+        recorder.merge(written);
+        res.map(|_| repaired)
+    }
+
+    /// Runs the preflight checks, see `preflight::check`.
+    async fn preflight(&self, extra_dirs: Vec<PathBuf>) -> Result<(), InstallErr> {
+        self.progress.event(&ProgressEvent::Phase(Phase::Preflight));
+        preflight::check(self, extra_dirs).await
+    }
+
+    /// Applies the conflict policies to destinations that already exist:
+    /// skipped tasks are dropped, and files in the way are moved aside.
+    /// Returns the destinations moved aside by `Overwrite`, whose backups are
+    /// deleted once the install succeeds.
+    async fn resolve_conflicts(
+        &mut self,
+        recorder: &mut recorder::Recorder,
+    ) -> Result<HashSet<PathBuf>, InstallErr> {
+        let mut overwritten = HashSet::new();
+        let mut file_tasks = Vec::with_capacity(self.file_tasks.len());
+        for task in std::mem::take(&mut self.file_tasks) {
+            let policy = task.conflict().unwrap_or(self.conflict);
+            if resolve_conflict(task.to(), policy, recorder, &mut overwritten).await? {
+                file_tasks.push(task);
+            }
+        }
+        self.file_tasks = file_tasks;
+        let mut link_tasks = Vec::with_capacity(self.link_tasks.len());
+        for task in std::mem::take(&mut self.link_tasks) {
+            let policy = task.conflict().unwrap_or(self.conflict);
+            if resolve_conflict(task.to(), policy, recorder, &mut overwritten).await? {
+                link_tasks.push(task);
+            }
+        }
+        self.link_tasks = link_tasks;
+        Ok(overwritten)
+    }
+
+    /// Splits into the tasks written into `staging` in place of `root`, and
+    /// the tasks applied directly afterwards.
+    fn split_staged(self, root: &Path, staging: &Path) -> (Installer, Installer) {
+        let stage = |path: &Path| match path.strip_prefix(root) {
+            Ok(relative) if relative.as_os_str().is_empty() => Some(staging.to_path_buf()),
+            Ok(relative) => Some(staging.join(relative)),
+            Err(_) => None,
+        };
+        let mut staged = Installer::new(
+            self.metadata.clone(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+        );
+        let mut after = Installer::new(
+            self.metadata,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            self.env_tasks,
+        );
+        staged.concurrency = self.concurrency;
+        staged.progress = self.progress.clone();
+        after.progress = self.progress;
+        for mut task in self.dir_tasks {
+            match stage(task.path()) {
+                Some(path) => {
+                    task.path = path;
+                    staged.dir_tasks.push(task);
+                }
+                None => after.dir_tasks.push(task),
+            }
+        }
+        for mut task in self.file_tasks {
+            match stage(task.to()) {
+                Some(path) => {
+                    *task.to_mut() = path;
+                    staged.file_tasks.push(task);
+                }
+                None => after.file_tasks.push(task),
+            }
+        }
+        for mut task in self.link_tasks {
+            match stage(task.to()) {
+                Some(path) => {
+                    task.to = path;
+                    // A hard link needs its original to exist already.
+                    if let LinkType::Hard = task.link_type
+                        && let Some(from) = stage(task.from())
+                    {
+                        task.from = from;
+                    }
+                    staged.link_tasks.push(task);
+                }
+                None => after.link_tasks.push(task),
+            }
+        }
+        (staged, after)
+    }
+
+    async fn apply(self, recorder: &mut recorder::Recorder) -> Result<(), InstallErr> {
+        let progress = self.progress;
+        if !self.dir_tasks.is_empty() {
+            progress.event(&ProgressEvent::Phase(Phase::CreatingDirectories));
+        }
+        for task in &self.dir_tasks {
+            create_directory(task.path(), task.attributes(), recorder).await?;
+        }
+        if !self.file_tasks.is_empty() {
+            progress.event(&ProgressEvent::Phase(Phase::WritingFiles));
+            write_files(self.file_tasks, self.concurrency, &progress, recorder).await?;
+        }
+        if !self.link_tasks.is_empty() {
+            progress.event(&ProgressEvent::Phase(Phase::CreatingLinks));
+        }
+        for task in self.link_tasks {
+            // Links often go into directories of the profile, such as its
+            // `bin_dir`, that nothing else creates.
+            if let Some(parent) = task.to().parent() {
+                create_directory(parent, &Attributes::default(), recorder).await?;
+            }
+            let res = match task.link_type() {
+                LinkType::Shortcut => bundle_deploy::link::shortcut(task.from(), task.to()).await,
+                LinkType::Symbolic => bundle_deploy::link::symlink(task.from(), task.to()).await,
+                LinkType::Hard => bundle_deploy::link::hard_link(task.from(), task.to()).await,
+            };
             match res {
+                Ok(_) => recorder.record_link(recorder::LinkRecord::from(task.to().clone())),
+                Err(source) => {
+                    let from = task.from().clone();
+                    let to = task.to().clone();
+                    return Err(InstallErr::CreateLink { from, to, source });
+                }
+            }
+        }
+        // Directory modes are set once their contents are written, so that a
+        // read-only directory can still be filled.
+        for task in self.dir_tasks.iter().rev() {
+            let res =
+                bundle_deploy::file_system::set_attributes(task.path(), task.attributes()).await;
+            if let Err(source) = res {
+                let path = task.path().clone();
+                return Err(InstallErr::SetAttributes { path, source });
+            }
+        }
+        if !self.env_tasks.is_empty() {
+            progress.event(&ProgressEvent::Phase(Phase::ApplyingEnv));
+        }
+        for task in self.env_tasks {
+            if let Some(parent) = task.target().path.parent() {
+                create_directory(parent, &Attributes::default(), recorder).await?;
+            }
+            match bundle_deploy::env::apply(task.target(), task.change()).await {
+                Ok(applied) => recorder.record_env(recorder::EnvRecord::new(
+                    task.target().path.clone(),
+                    applied.line,
+                    applied.created,
+                )),
+                Err(source) => {
+                    let path = task.target().path.clone();
+                    return Err(InstallErr::Env { path, source });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Creates `path` and whichever of its parents are missing, recording every
+/// directory it creates, parents first. Directories that already existed are
+/// not recorded, so rolling back never removes them. `attributes` are
+/// recorded for `path` only.
+async fn create_directory(
+    path: &Path,
+    attributes: &Attributes,
+    recorder: &mut recorder::Recorder,
+) -> Result<(), InstallErr> {
+    let mut missing = Vec::new();
+    for ancestor in path.ancestors() {
+        if ancestor.as_os_str().is_empty() || exists(ancestor).await {
+            break;
+        }
+        missing.push(ancestor);
+    }
+    if missing.is_empty() {
+        return match bundle_deploy::file_system::metadata(path).await {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => Err(InstallErr::CreateDirectory {
+                path: path.to_path_buf(),
+                source: io::Error::from(io::ErrorKind::AlreadyExists),
+            }),
+            Err(source) => Err(InstallErr::CreateDirectory {
+                path: path.to_path_buf(),
+                source,
+            }),
+        };
+    }
+    for dir in missing.into_iter().rev() {
+        match bundle_deploy::file_system::create_dir(dir).await {
+            Ok(_) => {
+                let attributes = if dir == path {
+                    *attributes
+                } else {
+                    Attributes::default()
+                };
+                recorder.record_directory(recorder::DirectoryRecord::new(
+                    dir.to_path_buf(),
+                    &attributes,
+                ));
+            }
+            // Created by someone else in the meantime, so not ours to remove.
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(source) => {
+                let path = dir.to_path_buf();
+                return Err(InstallErr::CreateDirectory { path, source });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// What `Installer::repair` re-applied.
+#[derive(Debug)]
+pub struct Repaired {
+    pub directories: Vec<PathBuf>,
+    pub files: Vec<PathBuf>,
+    pub links: Vec<PathBuf>,
+    /// Damaged files the script no longer writes.
+    pub unrepairable: Vec<PathBuf>,
+}
+
+/// Makes way for a task writing to `to`, returning whether it should run.
+/// `to` is added to `overwritten` when `Overwrite` moved it aside.
+async fn resolve_conflict(
+    to: &Path,
+    policy: ConflictPolicy,
+    recorder: &mut recorder::Recorder,
+    overwritten: &mut HashSet<PathBuf>,
+) -> Result<bool, InstallErr> {
+    let Ok(metadata) = bundle_deploy::file_system::symlink_metadata(to).await else {
+        return Ok(true);
+    };
+    let conflict = || InstallErr::Conflict {
+        path: to.to_path_buf(),
+        policy,
+    };
+    match policy {
+        ConflictPolicy::Fail => Err(conflict()),
+        ConflictPolicy::Skip => Ok(false),
+        // Directories are never moved or removed to make way for a file.
+        _ if metadata.is_dir() => Err(conflict()),
+        ConflictPolicy::Backup | ConflictPolicy::Overwrite => {
+            let backup = backup_path(to);
+            match bundle_deploy::file_system::rename(to, &backup).await {
                 Ok(_) => {
-                    recorder.record_directory(recorder::DirectoryRecord::from(task.path().clone()))
+                    recorder.record_backup(recorder::BackupRecord::new(to.to_path_buf(), backup));
+                    if policy == ConflictPolicy::Overwrite {
+                        overwritten.insert(to.to_path_buf());
+                    }
+                    Ok(true)
                 }
-                Err(_) => return Err((recorder, InstallErr::CreateDirectory(task.path().clone()))),
+                Err(source) => Err(InstallErr::Backup {
+                    path: to.to_path_buf(),
+                    source,
+                }),
             }
         }
-        for task in self.file_tasks {
-            match task {
-                WriteFileTask::FromPath { from, to } => {
-                    bundle_deploy::file_system::copy(from, &to).await.unwrap(); // todo
-                    recorder.record_file(recorder::FileRecord::from(to));
+    }
+}
+
+/// Deletes the files that `Overwrite` moved aside, now that the install they
+/// made way for has succeeded, and drops their backup records. A backup that
+/// cannot be deleted is left next to the new file.
+async fn discard_overwritten(recorder: &mut recorder::Recorder, overwritten: &HashSet<PathBuf>) {
+    for record in recorder.take_backups(overwritten) {
+        let _ = bundle_deploy::file_system::remove_file(record.backup()).await;
+    }
+}
+
+/// A free name next to `path` to move it to.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".veridian-backup-{}", Uuid::new_v4().simple()));
+    path.with_file_name(name)
+}
+
+async fn exists(path: &Path) -> bool {
+    bundle_deploy::file_system::symlink_metadata(path)
+        .await
+        .is_ok()
+}
+
+/// A unit of work for one file worker.
+enum FileJob {
+    Single(WriteFileTask),
+    /// Entries of one archive, extracted in a single pass by one worker. A tar
+    /// archive can only be read front to back, so the entries of an archive
+    /// are never spread over several workers; the concurrency only applies
+    /// across sources.
+    Archive {
+        archive: PathBuf,
+        entries: Vec<(PathBuf, PathBuf)>,
+    },
+}
+
+impl FileJob {
+    fn destinations(&self) -> Vec<PathBuf> {
+        match self {
+            FileJob::Single(task) => vec![task.to().clone()],
+            FileJob::Archive { entries, .. } => entries.iter().map(|(_, to)| to.clone()).collect(),
+        }
+    }
+
+    /// Runs the job, returning every destination written before any failure
+    /// with the hash of what was written to it.
+    async fn run(self, progress: FileProgress) -> (Vec<Written>, Option<InstallErr>) {
+        if let FileJob::Single(task) = &self {
+            progress.started(task.to());
+        }
+        match self {
+            FileJob::Single(WriteFileTask::FromPath { from, to, .. }) => {
+                let hash = recorder::ContentHash::default();
+                let res =
+                    bundle_deploy::file_system::copy_tee(from.clone(), to.clone(), hash).await;
+                match res {
+                    Ok((bytes, hash)) => {
+                        progress.finished(&to, bytes);
+                        (vec![(to, hash)], None)
+                    }
+                    Err(WriteError { source, touched }) => {
+                        if touched {
+                            remove_partial(&to).await;
+                        }
+                        let from = Some(from);
+                        (Vec::new(), Some(InstallErr::WriteFile { from, to, source }))
+                    }
+                }
+            }
+            FileJob::Single(WriteFileTask::Contents { content, to, .. }) => {
+                let bytes = content.len() as u64;
+                let mut hash = recorder::ContentHash::default();
+                hash.update(&content);
+                match bundle_deploy::file_system::write(&to, content).await {
+                    Ok(_) => {
+                        progress.finished(&to, bytes);
+                        (vec![(to, hash)], None)
+                    }
+                    Err(WriteError { source, touched }) => {
+                        if touched {
+                            remove_partial(&to).await;
+                        }
+                        let from = None;
+                        (Vec::new(), Some(InstallErr::WriteFile { from, to, source }))
+                    }
                 }
-                WriteFileTask::Contents { content, to } => {
-                    bundle_deploy::file_system::write(&to, content)
-                        .await
-                        .unwrap(); // todo
-                    recorder.record_file(recorder::FileRecord::from(to));
+            }
+            FileJob::Single(WriteFileTask::FromArchive {
+                archive, entry, to, ..
+            }) => extract(archive, vec![(entry, to)], progress).await,
+            FileJob::Archive { archive, entries } => extract(archive, entries, progress).await,
+        }
+    }
+}
+
+/// A destination that was written, with the hash of its contents.
+type Written = (PathBuf, recorder::ContentHash);
+
+async fn extract(
+    archive: PathBuf,
+    entries: Vec<(PathBuf, PathBuf)>,
+    progress: FileProgress,
+) -> (Vec<Written>, Option<InstallErr>) {
+    let destinations: Vec<PathBuf> = entries.iter().map(|(_, to)| to.clone()).collect();
+    let hashes = Arc::new(std::sync::Mutex::new(vec![
+        recorder::ContentHash::default();
+        entries.len()
+    ]));
+    let entry_hashes = hashes.clone();
+    let on_event = move |event: bundle_deploy::archive::ExtractEvent<'_>| match event {
+        bundle_deploy::archive::ExtractEvent::Started(index) => {
+            progress.started(&destinations[index])
+        }
+        bundle_deploy::archive::ExtractEvent::Data { index, data } => {
+            entry_hashes.lock().unwrap()[index].update(data)
+        }
+        bundle_deploy::archive::ExtractEvent::Finished { index, bytes } => {
+            progress.finished(&destinations[index], bytes)
+        }
+    };
+    let res = bundle_deploy::archive::extract(archive.clone(), entries.clone(), on_event).await;
+    let written = match &res {
+        Ok(_) => entries.len(),
+        Err((written, _)) => *written,
+    };
+    let hashes = std::mem::take(&mut *hashes.lock().unwrap());
+    let mut entries = entries.into_iter();
+    let written = entries
+        .by_ref()
+        .take(written)
+        .map(|(_, to)| to)
+        .zip(hashes)
+        .collect();
+    match res {
+        Ok(_) => (written, None),
+        Err((_, source)) => {
+            let (entry, to) = entries.next().unwrap();
+            let from = Some(archive.join(entry));
+            (written, Some(InstallErr::WriteFile { from, to, source }))
+        }
+    }
+}
+
+/// Applies the requested attributes to each written file and records its
+/// state, with the hash taken while it was written. A file whose attributes
+/// cannot be set or whose metadata cannot be read is still recorded, by path
+/// only, so that it is rolled back with the rest.
+async fn capture(
+    written: Vec<Written>,
+    attributes: &HashMap<PathBuf, Attributes>,
+) -> (Vec<recorder::FileRecord>, Option<InstallErr>) {
+    let mut records = Vec::with_capacity(written.len());
+    let mut error = None;
+    for (to, hash) in written {
+        if let Some(attributes) = attributes.get(&to)
+            && let Err(source) = bundle_deploy::file_system::set_attributes(&to, attributes).await
+        {
+            records.push(recorder::FileRecord::from(to.clone()));
+            error.get_or_insert(InstallErr::SetAttributes { path: to, source });
+            continue;
+        }
+        match recorder::FileState::written(&to, hash).await {
+            Ok(state) => records.push(recorder::FileRecord::new(to, state)),
+            Err(source) => {
+                records.push(recorder::FileRecord::from(to.clone()));
+                error.get_or_insert(InstallErr::WriteFile {
+                    from: None,
+                    to,
+                    source,
+                });
+            }
+        }
+    }
+    (records, error)
+}
+
+/// Reports the progress of the file workers, which share the running total.
+#[derive(Clone)]
+struct FileProgress {
+    progress: Arc<dyn Progress>,
+    copied: Arc<AtomicU64>,
+    total: u64,
+}
+
+impl FileProgress {
+    fn started(&self, to: &Path) {
+        self.progress.event(&ProgressEvent::FileStarted { to });
+    }
+
+    fn finished(&self, to: &Path, bytes: u64) {
+        self.progress
+            .event(&ProgressEvent::FileFinished { to, bytes });
+        let copied = self.copied.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.progress.event(&ProgressEvent::Bytes {
+            copied,
+            total: self.total,
+        });
+    }
+}
+
+/// Sums the sizes of every file task, counting unreadable sources as empty.
+async fn total_bytes(tasks: &[WriteFileTask]) -> u64 {
+    let mut total = 0;
+    for task in tasks {
+        total += task.size().await.unwrap_or(0);
+    }
+    total
+}
+
+fn group_file_tasks(tasks: Vec<WriteFileTask>) -> Vec<FileJob> {
+    let mut jobs = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        match task {
+            WriteFileTask::FromArchive {
+                archive, entry, to, ..
+            } => match jobs.last_mut() {
+                Some(FileJob::Archive {
+                    archive: last,
+                    entries,
+                }) if *last == archive => entries.push((entry, to)),
+                _ => jobs.push(FileJob::Archive {
+                    archive,
+                    entries: vec![(entry, to)],
+                }),
+            },
+            task => jobs.push(FileJob::Single(task)),
+        }
+    }
+    jobs
+}
+
+/// Writes the files with at most `concurrency` jobs in flight.
+///
+/// No new job is started after one fails, but those already running are
+/// waited for. Everything written is then recorded in task order, so the
+/// recorder is the same whatever order the workers finished in, and the error
+/// of the earliest failed task is returned. A job that panics fails with
+/// `InstallErr::Worker`, and whichever of its destinations exist are recorded
+/// by path so that they are rolled back.
+async fn write_files(
+    tasks: Vec<WriteFileTask>,
+    concurrency: usize,
+    progress: &Arc<dyn Progress>,
+    recorder: &mut recorder::Recorder,
+) -> Result<(), InstallErr> {
+    let file_progress = FileProgress {
+        progress: progress.clone(),
+        copied: Arc::new(AtomicU64::new(0)),
+        total: total_bytes(&tasks).await,
+    };
+    progress.event(&ProgressEvent::Bytes {
+        copied: 0,
+        total: file_progress.total,
+    });
+    let attributes: Arc<HashMap<PathBuf, Attributes>> = Arc::new(
+        tasks
+            .iter()
+            .filter(|t| !t.attributes().is_empty())
+            .map(|t| (t.to().clone(), *t.attributes()))
+            .collect(),
+    );
+    let jobs = group_file_tasks(tasks);
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut join_set = JoinSet::<(usize, Vec<recorder::FileRecord>, Option<InstallErr>)>::new();
+    let mut spawned = HashMap::new();
+    let mut results = Vec::with_capacity(jobs.len());
+    let mut failed = false;
+    for (index, job) in jobs.into_iter().enumerate() {
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        while let Some(res) = join_set.try_join_next() {
+            let res = job_result(res, &mut spawned).await;
+            failed |= res.2.is_some();
+            results.push(res);
+        }
+        if failed {
+            break;
+        }
+        let destinations = job.destinations();
+        let file_progress = file_progress.clone();
+        let attributes = attributes.clone();
+        let handle = join_set.spawn(async move {
+            let _permit = permit;
+            let (written, error) = job.run(file_progress).await;
+            let (records, capture_error) = capture(written, &attributes).await;
+            (index, records, error.or(capture_error))
+        });
+        spawned.insert(handle.id(), (index, destinations));
+    }
+    while let Some(res) = join_set.join_next().await {
+        results.push(job_result(res, &mut spawned).await);
+    }
+    results.sort_by_key(|(index, _, _)| *index);
+    let mut first_error = None;
+    for (_, written, error) in results {
+        for record in written {
+            recorder.record_file(record);
+        }
+        if first_error.is_none() {
+            first_error = error;
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// The result of a finished file job, standing in for one that panicked.
+async fn job_result(
+    res: Result<(usize, Vec<recorder::FileRecord>, Option<InstallErr>), tokio::task::JoinError>,
+    spawned: &mut HashMap<tokio::task::Id, (usize, Vec<PathBuf>)>,
+) -> (usize, Vec<recorder::FileRecord>, Option<InstallErr>) {
+    match res {
+        Ok(res) => res,
+        Err(e) => {
+            let (index, destinations) = spawned.remove(&e.id()).unwrap_or_default();
+            let mut records = Vec::new();
+            for to in destinations {
+                if exists(&to).await {
+                    records.push(recorder::FileRecord::from(to));
                 }
             }
+            (index, records, Some(InstallErr::Worker(e)))
+        }
+    }
+}
+
+fn finish(
+    mut metadata: application::Metadata,
+    recorder: recorder::Recorder,
+) -> application::Application {
+    metadata.installed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    application::Application::new(Uuid::new_v4(), metadata, recorder)
+}
+
+fn unstage(path: PathBuf, staging: &Path, root: &Path) -> PathBuf {
+    match path.strip_prefix(staging) {
+        Ok(relative) if relative.as_os_str().is_empty() => root.to_path_buf(),
+        Ok(relative) => root.join(relative),
+        Err(_) => path,
+    }
+}
+
+/// Checks that everything recorded in the staging directory is there, and
+/// that every file still has the size and contents it was written with.
+async fn verify_staged(recorder: &recorder::Recorder) -> Result<(), InstallErr> {
+    let mut issues = recorder.verify().await;
+    for record in recorder.links() {
+        if !exists(record.path()).await {
+            issues.push(recorder::VerifyIssue::Missing(record.path().clone()));
+        }
+    }
+    if issues.is_empty() {
+        Ok(())
+    } else {
+        Err(InstallErr::Verify(issues))
+    }
+}
+
+/// Removes the partial file a failed write left at `to`. Only called when the
+/// write got as far as creating or truncating `to`, so that a file that was
+/// there before is never removed.
+async fn remove_partial(to: &Path) {
+    let _ = bundle_deploy::file_system::remove_file(to).await;
+}
+
+/// Moves every top-level entry of `staging` into `root`.
+///
+/// Nothing is moved if an entry would replace anything but a directory in
+/// `root`. Such an entry appeared while the install was staged and is not ours
+/// to replace.
+async fn swap(staging: &Path, root: &Path) -> Result<(), InstallErr> {
+    let swap_err = |source| InstallErr::Swap {
+        staging: staging.to_path_buf(),
+        root: root.to_path_buf(),
+        source,
+    };
+    let mut collisions = Vec::new();
+    staged_collisions(staging.to_path_buf(), root.to_path_buf(), &mut collisions)
+        .await
+        .map_err(swap_err)?;
+    if !collisions.is_empty() {
+        return Err(InstallErr::SwapConflict(collisions));
+    }
+    let mut read_dir = bundle_deploy::file_system::read_dir(staging)
+        .await
+        .map_err(swap_err)?;
+    while let Some(entry) = read_dir.next_entry().await.map_err(swap_err)? {
+        bundle_deploy::file_system::move_tree(entry.path(), root.join(entry.file_name()))
+            .await
+            .map_err(swap_err)?;
+    }
+    Ok(())
+}
+
+/// Collects the paths under `root` that moving the entries of `staging` there
+/// would replace, leaving out directories that are merged into.
+async fn staged_collisions(
+    staging: PathBuf,
+    root: PathBuf,
+    collisions: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let mut read_dir = bundle_deploy::file_system::read_dir(&staging).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let to = root.join(entry.file_name());
+        let Ok(existing) = bundle_deploy::file_system::symlink_metadata(&to).await else {
+            continue;
+        };
+        if existing.is_dir() && entry.file_type().await?.is_dir() {
+            Box::pin(staged_collisions(entry.path(), to, collisions)).await?;
+        } else {
+            collisions.push(to);
         }
-        // todo!()
-        Ok(application::Application::from(recorder))
     }
+    Ok(())
 }
 
 #[derive(Debug)]
 pub enum InstallErr {
-    CreateDirectory(PathBuf),
-    WriteFile,
-    CreateLink,
-    Env,
+    CreateDirectory {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// `from` is `None` when the file was written from in-memory contents.
+    WriteFile {
+        from: Option<PathBuf>,
+        to: PathBuf,
+        source: io::Error,
+    },
+    CreateLink {
+        from: PathBuf,
+        to: PathBuf,
+        source: io::Error,
+    },
+    Env {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// The install was stopped before writing anything.
+    Preflight(preflight::PreflightErr),
+    /// Destinations that belong to other applications, with their owner.
+    /// Nothing was written.
+    Owned(Vec<(PathBuf, Uuid)>),
+    /// Setting the mode or ownership of `path` failed.
+    SetAttributes {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// `path` already exists and `policy` does not allow replacing it.
+    Conflict {
+        path: PathBuf,
+        policy: ConflictPolicy,
+    },
+    /// Moving the existing file at `path` aside failed.
+    Backup {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Staged files differ from what was written, or are missing.
+    Verify(Vec<recorder::VerifyIssue>),
+    /// Paths that appeared in the final destination while the install was
+    /// staged. Nothing was moved into place.
+    SwapConflict(Vec<PathBuf>),
+    /// A file worker or the preflight check panicked or was cancelled.
+    Worker(tokio::task::JoinError),
+    /// Moving the staged files from `staging` into `root` failed.
+    Swap {
+        staging: PathBuf,
+        root: PathBuf,
+        source: io::Error,
+    },
 }
 
 impl std::fmt::Display for InstallErr {
@@ -108,6 +1258,548 @@ impl std::fmt::Display for InstallErr {
     }
 }
 
-impl std::error::Error for InstallErr {}
+impl std::error::Error for InstallErr {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InstallErr::CreateDirectory { source, .. }
+            | InstallErr::WriteFile { source, .. }
+            | InstallErr::CreateLink { source, .. }
+            | InstallErr::Env { source, .. }
+            | InstallErr::SetAttributes { source, .. }
+            | InstallErr::Backup { source, .. }
+            | InstallErr::Swap { source, .. } => Some(source),
+            InstallErr::Preflight(e) => Some(e),
+            InstallErr::Worker(e) => Some(e),
+            InstallErr::Verify(_)
+            | InstallErr::SwapConflict(_)
+            | InstallErr::Owned(_)
+            | InstallErr::Conflict { .. } => None,
+        }
+    }
+}
 
 pub type InstallResult = Result<application::Application, (recorder::Recorder, InstallErr)>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "veridian-installer-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Runtime::new().unwrap().block_on(future)
+    }
+
+    #[test]
+    fn swap_refuses_to_replace_existing_files() {
+        let dir = temp_dir("swap");
+        let staging = dir.join("staging");
+        let root = dir.join("root");
+        std::fs::create_dir_all(staging.join("shared")).unwrap();
+        std::fs::create_dir_all(root.join("shared")).unwrap();
+        std::fs::write(staging.join("new"), "ours").unwrap();
+        std::fs::write(staging.join("shared/file"), "ours").unwrap();
+        std::fs::write(root.join("shared/file"), "theirs").unwrap();
+
+        let err = block_on(swap(&staging, &root)).unwrap_err();
+        match err {
+            InstallErr::SwapConflict(paths) => assert_eq!(paths, vec![root.join("shared/file")]),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(std::fs::read(root.join("shared/file")).unwrap(), b"theirs");
+        assert!(!root.join("new").exists());
+
+        std::fs::remove_file(root.join("shared/file")).unwrap();
+        block_on(swap(&staging, &root)).unwrap();
+        assert_eq!(std::fs::read(root.join("shared/file")).unwrap(), b"ours");
+        assert_eq!(std::fs::read(root.join("new")).unwrap(), b"ours");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_created_directories_are_rolled_back() {
+        let dir = temp_dir("created-dirs");
+        let existing = dir.join("dest/bin");
+        std::fs::create_dir_all(&existing).unwrap();
+        let installer = |from: &Path| {
+            Installer::new(
+                application::Metadata::default(),
+                vec![
+                    CreateDirectoryTask::new(existing.clone()),
+                    CreateDirectoryTask::new(dir.join("dest/share/demo")),
+                ],
+                vec![WriteFileTask::FromPath {
+                    from: from.to_path_buf(),
+                    to: existing.join("demo"),
+                    conflict: None,
+                    attributes: Attributes::default(),
+                }],
+                Vec::new(),
+                Vec::new(),
+            )
+        };
+
+        let Err((recorder, _)) = block_on(installer(&dir.join("missing")).install()) else {
+            panic!("installing from a missing source succeeded");
+        };
+        block_on(recorder.rollback()).unwrap();
+        assert!(existing.is_dir());
+        assert!(!dir.join("dest/share").exists());
+
+        let source = dir.join("source");
+        std::fs::write(&source, "demo").unwrap();
+        let application = block_on(installer(&source).install())
+            .map_err(|(_, e)| e)
+            .unwrap();
+        // Uninstalling rolls back the recorder stored in the database.
+        let stored = application.recorder().to_binary().unwrap();
+        let recorder = recorder::Recorder::from_binary(&stored).unwrap();
+        block_on(recorder.rollback()).unwrap();
+        assert!(existing.is_dir());
+        assert!(!existing.join("demo").exists());
+        assert!(!dir.join("dest/share").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_created_directories_are_rolled_back_after_a_staged_install() {
+        let dir = temp_dir("staged-dirs");
+        let installer = |root: &Path| {
+            Installer::new(
+                application::Metadata::default(),
+                vec![
+                    CreateDirectoryTask::new(root.join("demo")),
+                    CreateDirectoryTask::new(root.join("demo/sub")),
+                    CreateDirectoryTask::new(root.join("demo/new")),
+                ],
+                vec![WriteFileTask::Contents {
+                    content: b"demo".to_vec(),
+                    to: root.join("demo/sub/file"),
+                    conflict: None,
+                    attributes: Attributes::default(),
+                }],
+                Vec::new(),
+                Vec::new(),
+            )
+        };
+        let created = |recorder: &recorder::Recorder| -> Vec<PathBuf> {
+            recorder
+                .directories()
+                .iter()
+                .map(|r| r.path().clone())
+                .collect()
+        };
+
+        let root = dir.join("install");
+        std::fs::create_dir_all(root.join("demo/sub")).unwrap();
+        let application = block_on(installer(&root).install_staged(&root))
+            .map_err(|(_, e)| e)
+            .unwrap();
+        assert_eq!(created(application.recorder()), vec![root.join("demo/new")]);
+        block_on(application.into_recorder().rollback()).unwrap();
+        assert!(root.join("demo/sub").is_dir());
+        assert!(!root.join("demo/sub/file").exists());
+        assert!(!root.join("demo/new").exists());
+
+        // A missing root is created along with its parents, and removed again.
+        let root = dir.join("fresh/install");
+        let application = block_on(installer(&root).install_staged(&root))
+            .map_err(|(_, e)| e)
+            .unwrap();
+        assert_eq!(
+            created(application.recorder()),
+            vec![
+                dir.join("fresh"),
+                root.clone(),
+                root.join("demo"),
+                root.join("demo/sub"),
+                root.join("demo/new"),
+            ]
+        );
+        block_on(application.into_recorder().rollback()).unwrap();
+        assert!(!dir.join("fresh").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn links_are_created_and_rolled_back() {
+        let dir = temp_dir("links");
+        let program = dir.join("demo");
+        std::fs::write(&program, "demo").unwrap();
+        let installer = Installer::new(
+            application::Metadata::default(),
+            Vec::new(),
+            Vec::new(),
+            vec![
+                CreateLinkTask::new(program.clone(), dir.join("symbolic"), LinkType::Symbolic),
+                CreateLinkTask::new(program.clone(), dir.join("hard"), LinkType::Hard),
+            ],
+            Vec::new(),
+        );
+        let application = block_on(installer.install()).map_err(|(_, e)| e).unwrap();
+        assert_eq!(std::fs::read_link(dir.join("symbolic")).unwrap(), program);
+        assert_eq!(std::fs::read(dir.join("hard")).unwrap(), b"demo");
+
+        let stored = application.recorder().to_binary().unwrap();
+        block_on(recorder::Recorder::from_binary(&stored).unwrap().rollback()).unwrap();
+        assert!(std::fs::symlink_metadata(dir.join("symbolic")).is_err());
+        assert!(!dir.join("hard").exists());
+        assert_eq!(std::fs::read(&program).unwrap(), b"demo");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn links_are_created_in_missing_directories() {
+        let dir = temp_dir("link-parents");
+        let program = dir.join("demo");
+        std::fs::write(&program, "demo").unwrap();
+        let bin_dir = dir.join("profile/bin");
+        let installer = Installer::new(
+            application::Metadata::default(),
+            Vec::new(),
+            Vec::new(),
+            vec![CreateLinkTask::new(
+                program.clone(),
+                bin_dir.join("demo"),
+                LinkType::Symbolic,
+            )],
+            Vec::new(),
+        );
+        let application = block_on(installer.install()).map_err(|(_, e)| e).unwrap();
+        assert_eq!(std::fs::read_link(bin_dir.join("demo")).unwrap(), program);
+        let created: Vec<_> = application
+            .recorder()
+            .directories()
+            .iter()
+            .map(|r| r.path().clone())
+            .collect();
+        assert_eq!(created, vec![dir.join("profile"), bin_dir]);
+        block_on(application.into_recorder().rollback()).unwrap();
+        assert!(!dir.join("profile").exists());
+        assert!(program.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn env_file_directories_are_rolled_back() {
+        let dir = temp_dir("env-dirs");
+        let env_file = dir.join("config/environment.d/60-veridian-manager.conf");
+        let target = bundle_deploy::env::EnvTarget::new(
+            env_file.clone(),
+            bundle_deploy::env::EnvFileFormat::EnvironmentD,
+        );
+        let installer = Installer::new(
+            application::Metadata::default(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            vec![EnvTask::new(
+                target,
+                bundle_deploy::env::EnvChange::PrependPath(dir.join("bin")),
+            )],
+        );
+        let application = block_on(installer.install()).map_err(|(_, e)| e).unwrap();
+        assert!(env_file.exists());
+        let stored = application.recorder().to_binary().unwrap();
+        block_on(recorder::Recorder::from_binary(&stored).unwrap().rollback()).unwrap();
+        assert!(!dir.join("config").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_write_stops_the_install_midway() {
+        let dir = temp_dir("concurrency");
+        let missing = dir.join("missing");
+        let tasks = (0..8)
+            .map(|i| {
+                let to = dir.join(format!("file-{}", i));
+                if i == 4 {
+                    WriteFileTask::FromPath {
+                        from: missing.clone(),
+                        to,
+                        conflict: None,
+                        attributes: Attributes::default(),
+                    }
+                } else {
+                    WriteFileTask::Contents {
+                        content: vec![b'x'; 1024 * i],
+                        to,
+                        conflict: None,
+                        attributes: Attributes::default(),
+                    }
+                }
+            })
+            .collect();
+        let mut installer = Installer::new(
+            application::Metadata::default(),
+            Vec::new(),
+            tasks,
+            Vec::new(),
+            Vec::new(),
+        );
+        installer.set_concurrency(2);
+
+        let Err((recorder, err)) = block_on(installer.install()) else {
+            panic!("installing from a missing source succeeded");
+        };
+        assert!(
+            matches!(err, InstallErr::WriteFile { from: Some(ref from), .. } if *from == missing),
+            "{:?}",
+            err
+        );
+        let written: Vec<_> = recorder.files().iter().map(|r| r.path().clone()).collect();
+        let mut sorted = written.clone();
+        sorted.sort();
+        assert_eq!(written, sorted);
+        for i in 0..4 {
+            assert!(written.contains(&dir.join(format!("file-{}", i))));
+        }
+        assert!(!written.contains(&dir.join("file-4")));
+
+        block_on(recorder.rollback()).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_failed_write_keeps_a_destination_it_did_not_touch() {
+        let dir = temp_dir("untouched");
+        let to = dir.join("file");
+        std::fs::write(&to, "existing").unwrap();
+        let progress = FileProgress {
+            progress: Arc::new(progress::Silent),
+            copied: Arc::new(AtomicU64::new(0)),
+            total: 0,
+        };
+        let job = FileJob::Single(WriteFileTask::FromPath {
+            from: dir.join("missing"),
+            to: to.clone(),
+            conflict: None,
+            attributes: Attributes::default(),
+        });
+
+        let (written, err) = block_on(job.run(progress));
+        assert!(written.is_empty());
+        assert!(
+            matches!(err, Some(InstallErr::WriteFile { .. })),
+            "{:?}",
+            err
+        );
+        assert_eq!(std::fs::read(&to).unwrap(), b"existing");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn progress_is_reported_for_every_file() {
+        let dir = temp_dir("progress");
+        let tasks = (0..3)
+            .map(|i| WriteFileTask::Contents {
+                content: vec![b'x'; 100 * (i + 1)],
+                to: dir.join(format!("file-{}", i)),
+                conflict: None,
+                attributes: Attributes::default(),
+            })
+            .collect();
+        let mut installer = Installer::new(
+            application::Metadata::default(),
+            vec![CreateDirectoryTask::new(dir.join("sub"))],
+            tasks,
+            Vec::new(),
+            Vec::new(),
+        );
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let collected = events.clone();
+        installer.set_progress(Arc::new(move |event: &ProgressEvent<'_>| {
+            let event = match event {
+                ProgressEvent::Phase(phase) => format!("{:?}", phase),
+                ProgressEvent::FileStarted { .. } => "started".to_string(),
+                ProgressEvent::FileFinished { bytes, .. } => format!("finished {}", bytes),
+                ProgressEvent::Bytes { copied, total } => format!("bytes {}/{}", copied, total),
+                e => format!("{:?}", e),
+            };
+            collected.lock().unwrap().push(event);
+        }));
+        block_on(installer.install()).map_err(|(_, e)| e).unwrap();
+
+        let events = events.lock().unwrap();
+        let phases: Vec<_> = events
+            .iter()
+            .filter(|e| e.chars().next().is_some_and(char::is_uppercase))
+            .collect();
+        assert_eq!(
+            phases,
+            vec!["Preflight", "CreatingDirectories", "WritingFiles"]
+        );
+        let count = |prefix: &str| events.iter().filter(|e| e.starts_with(prefix)).count();
+        assert_eq!(count("started"), 3);
+        assert_eq!(count("finished"), 3);
+        let bytes: Vec<_> = events.iter().filter(|e| e.starts_with("bytes")).collect();
+        assert_eq!(bytes.first().unwrap().as_str(), "bytes 0/600");
+        assert_eq!(bytes.last().unwrap().as_str(), "bytes 600/600");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn written_files_are_hashed_while_streaming() {
+        let dir = temp_dir("hash");
+        let source = dir.join("source");
+        std::fs::write(&source, "from disk").unwrap();
+        let root = dir.join("root");
+        let tasks = vec![
+            WriteFileTask::FromPath {
+                from: source,
+                to: root.join("copied"),
+                conflict: None,
+                attributes: Attributes::default(),
+            },
+            WriteFileTask::Contents {
+                content: b"from memory".to_vec(),
+                to: root.join("written"),
+                conflict: None,
+                attributes: Attributes::default(),
+            },
+        ];
+        let installer = Installer::new(
+            application::Metadata::default(),
+            vec![CreateDirectoryTask::new(root.clone())],
+            tasks,
+            Vec::new(),
+            Vec::new(),
+        );
+        let application = block_on(installer.install()).map_err(|(_, e)| e).unwrap();
+        let files = application.recorder().files();
+        assert_eq!(files.len(), 2);
+        for record in files {
+            let read = block_on(recorder::FileState::read(record.path())).unwrap();
+            assert_eq!(record.state(), Some(&read), "{}", record.path().display());
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn overwritten_files_are_restored_when_an_install_fails() {
+        let dir = temp_dir("overwrite");
+        let to = dir.join("file");
+        std::fs::write(&to, "old").unwrap();
+        let installer = |from: &Path| {
+            Installer::new(
+                application::Metadata::default(),
+                Vec::new(),
+                vec![
+                    WriteFileTask::Contents {
+                        content: b"new".to_vec(),
+                        to: to.clone(),
+                        conflict: None,
+                        attributes: Attributes::default(),
+                    },
+                    WriteFileTask::FromPath {
+                        from: from.to_path_buf(),
+                        to: dir.join("other"),
+                        conflict: None,
+                        attributes: Attributes::default(),
+                    },
+                ],
+                Vec::new(),
+                Vec::new(),
+            )
+        };
+
+        let mut failing = installer(&dir.join("missing"));
+        failing.set_concurrency(1);
+        let Err((recorder, _)) = block_on(failing.install()) else {
+            panic!("installing from a missing source succeeded");
+        };
+        block_on(recorder.rollback()).unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"old");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let source = dir.join("source");
+        std::fs::write(&source, "other").unwrap();
+        let application = block_on(installer(&source).install())
+            .map_err(|(_, e)| e)
+            .unwrap();
+        assert_eq!(std::fs::read(&to).unwrap(), b"new");
+        // The old file is gone once the install succeeded, and uninstalling
+        // does not bring it back.
+        assert!(application.recorder().backups().is_empty());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify_staged_detects_modified_files() {
+        let dir = temp_dir("verify-staged");
+        let path = dir.join("file");
+        std::fs::write(&path, "written").unwrap();
+        let mut recorder = recorder::Recorder::default();
+        recorder.record_file(block_on(recorder::FileRecord::capture(path.clone())).unwrap());
+        block_on(verify_staged(&recorder)).unwrap();
+
+        std::fs::write(&path, "changed").unwrap();
+        let err = block_on(verify_staged(&recorder)).unwrap_err();
+        assert!(matches!(err, InstallErr::Verify(ref issues) if issues.len() == 1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn repair_restores_damaged_files_and_missing_links() {
+        let dir = temp_dir("repair");
+        let root = dir.join("root");
+        let installer = || {
+            Installer::new(
+                application::Metadata::default(),
+                vec![CreateDirectoryTask::new(root.clone())],
+                vec![
+                    WriteFileTask::Contents {
+                        content: b"program".to_vec(),
+                        to: root.join("program"),
+                        conflict: None,
+                        attributes: Attributes::default(),
+                    },
+                    WriteFileTask::Contents {
+                        content: b"readme".to_vec(),
+                        to: root.join("readme"),
+                        conflict: None,
+                        attributes: Attributes::default(),
+                    },
+                ],
+                vec![CreateLinkTask::new(
+                    root.join("program"),
+                    root.join("link"),
+                    LinkType::Symbolic,
+                )],
+                Vec::new(),
+            )
+        };
+        let application = block_on(installer().install()).map_err(|(_, e)| e).unwrap();
+        let mut recorder = application.into_recorder();
+        std::fs::write(root.join("program"), "damaged").unwrap();
+        std::fs::remove_file(root.join("link")).unwrap();
+        // Links are not verified, only recreated when missing.
+        assert_eq!(block_on(recorder.verify()).len(), 1);
+
+        let repaired = block_on(installer().repair(&mut recorder)).unwrap();
+        assert!(repaired.directories.is_empty());
+        assert_eq!(repaired.files, vec![root.join("program")]);
+        assert_eq!(repaired.links, vec![root.join("link")]);
+        assert!(repaired.unrepairable.is_empty());
+        assert_eq!(std::fs::read(root.join("program")).unwrap(), b"program");
+        assert!(block_on(recorder.verify()).is_empty());
+
+        block_on(recorder.rollback()).unwrap();
+        assert!(!root.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,13 @@
-use crate::installer;
-use bundle_deploy::file_system::{FileName, RelativePath};
+use crate::progress::{Phase, Progress, ProgressEvent};
+use crate::{application, installer, progress};
+use bundle_deploy::archive::ArchiveEntryKind;
+use bundle_deploy::file_system::{Attributes, FileName, RelativePath};
 use rhai::CustomType;
 use rhai::TypeBuilder;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::fmt::{Debug, Display, Formatter};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone)]
 pub enum SourcePath {
@@ -13,58 +15,97 @@ pub enum SourcePath {
     Archive(PathBuf, RelativePath, glob::Pattern),
 }
 
+/// Files, directories and links to install under `destination`.
+///
+/// The pattern of `path` is matched against the path of every entry relative
+/// to the source root, which is the directory of a disk source and the inner
+/// directory of an archive. Entries that do not match are skipped, but the
+/// directories they are in are still searched, and the parents of every
+/// matching entry are created.
 #[derive(Debug, Clone)]
 pub struct Source {
     pub path: SourcePath,
     pub destination: PathBuf,
+    /// Conflict policy of every task resolved from this source.
+    pub conflict: Option<installer::ConflictPolicy>,
+}
+
+/// Adds the parents of `relative_path` to `dirs`, keeping the mode of those
+/// already there.
+fn insert_parents(dirs: &mut BTreeMap<PathBuf, Option<u32>>, relative_path: &Path) {
+    for parent in relative_path.ancestors().skip(1) {
+        if !parent.as_os_str().is_empty() {
+            dirs.entry(parent.to_path_buf()).or_insert(None);
+        }
+    }
 }
 
 #[inline]
 fn resolve_stack_util(stack: &Vec<VecDeque<FileName>>) -> RelativePath {
     let mut path = Vec::new();
     for d in stack {
-        path.push(d.get(0).unwrap().clone());
+        path.push(d.front().unwrap().clone());
     }
     RelativePath::new(path).unwrap()
 }
 
 impl Source {
     pub fn resolve(&self) -> SourceResolveResult {
-        let mut dir_tasks = Vec::with_capacity(64);
+        self.resolve_with_progress(&progress::Silent)
+    }
+
+    /// Resolves the source, reporting every directory or archive it reads.
+    pub fn resolve_with_progress(&self, progress: &dyn Progress) -> SourceResolveResult {
         let mut file_tasks = Vec::with_capacity(128);
+        let mut link_tasks = Vec::new();
+        // Directories relative to the source root, with the mode of their
+        // archive entry if they have one.
+        let mut dirs = BTreeMap::new();
         match &self.path {
-            SourcePath::Disk(abs, pat) => {
+            SourcePath::Disk(root, pat) => {
                 let mut stack = Vec::<VecDeque<FileName>>::with_capacity(128);
                 'a: loop {
-                    let read_dir =
-                        match fs::read_dir(resolve_stack_util(&stack).resolve(abs).unwrap()) {
-                            Ok(read_dir) => read_dir,
-                            Err(e) => return Err(SourceResolveErr::ReadDirErr(e)),
-                        };
+                    let dir = resolve_stack_util(&stack).resolve(root).unwrap();
+                    let read_dir = match fs::read_dir(&dir) {
+                        Ok(read_dir) => read_dir,
+                        Err(e) => return Err(SourceResolveErr::ReadDirErr(e)),
+                    };
                     let mut dir_deque = VecDeque::new();
+                    let mut entries = 0;
                     for entry in read_dir {
                         let entry = match entry {
                             Ok(entry) => entry,
                             Err(e) => return Err(SourceResolveErr::ReadDirErr(e)),
                         };
+                        entries += 1;
                         let path = entry.path();
-                        if !pat.matches_path(&path) {
-                            continue;
-                        }
                         let mut relative_path = resolve_stack_util(&stack);
                         relative_path.push(FileName::new(entry.file_name()).unwrap());
+                        let relative_path = relative_path.resolve(PathBuf::new()).unwrap();
+                        let matches = pat.matches_path(&relative_path);
                         if path.is_file() {
-                            file_tasks.push(installer::WriteFileTask::FromPath {
-                                from: path,
-                                to: relative_path.resolve(&self.destination).unwrap(),
-                            });
+                            if matches {
+                                insert_parents(&mut dirs, &relative_path);
+                                file_tasks.push(installer::WriteFileTask::FromPath {
+                                    from: path,
+                                    to: self.destination.join(&relative_path),
+                                    conflict: self.conflict,
+                                    attributes: Attributes::default(),
+                                });
+                            }
                         } else if path.is_dir() {
-                            dir_tasks.push(installer::CreateDirectoryTask::new(
-                                relative_path.resolve(&self.destination).unwrap(),
-                            ));
+                            if matches {
+                                insert_parents(&mut dirs, &relative_path);
+                                dirs.insert(relative_path, None);
+                            }
+                            // Descended into either way, its entries may match.
                             dir_deque.push_back(FileName::new(entry.file_name()).unwrap());
                         }
                     }
+                    progress.event(&ProgressEvent::Scanned {
+                        path: &dir,
+                        entries,
+                    });
                     loop {
                         if !dir_deque.is_empty() {
                             stack.push(dir_deque);
@@ -79,13 +120,69 @@ impl Source {
                     }
                 }
             }
-            SourcePath::Archive(_, _, _) => {
-                todo!()
+            SourcePath::Archive(archive, inner, pat) => {
+                let inner = match inner.resolve(PathBuf::new()) {
+                    Ok(inner) => inner,
+                    Err(e) => return Err(SourceResolveErr::InnerPathErr(e)),
+                };
+                let entries = match bundle_deploy::archive::list(archive) {
+                    Ok(entries) => entries,
+                    Err(e) => return Err(SourceResolveErr::ArchiveErr(e)),
+                };
+                progress.event(&ProgressEvent::Scanned {
+                    path: archive,
+                    entries: entries.len(),
+                });
+                for entry in entries {
+                    let relative_path = match entry.path.strip_prefix(&inner) {
+                        Ok(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+                        _ => continue,
+                    };
+                    if !pat.matches_path(&relative_path) {
+                        continue;
+                    }
+                    insert_parents(&mut dirs, &relative_path);
+                    let to = self.destination.join(&relative_path);
+                    match entry.kind {
+                        ArchiveEntryKind::Directory => {
+                            dirs.insert(relative_path, entry.mode);
+                        }
+                        ArchiveEntryKind::File { size } => {
+                            file_tasks.push(installer::WriteFileTask::FromArchive {
+                                archive: archive.clone(),
+                                entry: entry.path,
+                                size,
+                                to,
+                                conflict: self.conflict,
+                                attributes: Attributes::default(),
+                            });
+                        }
+                        ArchiveEntryKind::Symlink(target) => {
+                            let mut task = installer::CreateLinkTask::new(
+                                target,
+                                to,
+                                installer::LinkType::Symbolic,
+                            );
+                            task.set_conflict(self.conflict);
+                            link_tasks.push(task);
+                        }
+                    }
+                }
             }
         }
+        // Sorted paths put every parent before its children.
+        let dir_tasks = dirs
+            .into_iter()
+            .map(|(path, mode)| {
+                let mut task = installer::CreateDirectoryTask::new(self.destination.join(path));
+                task.attributes_mut().mode = mode;
+                task
+            })
+            .collect();
         Ok(SourceResolveOK {
             dir_tasks,
             file_tasks,
+            link_tasks,
         })
     }
 }
@@ -94,11 +191,14 @@ impl Source {
 pub struct SourceResolveOK {
     pub dir_tasks: Vec<installer::CreateDirectoryTask>,
     pub file_tasks: Vec<installer::WriteFileTask>,
+    pub link_tasks: Vec<installer::CreateLinkTask>,
 }
 
 #[derive(Debug)]
 pub enum SourceResolveErr {
     ReadDirErr(std::io::Error),
+    ArchiveErr(std::io::Error),
+    InnerPathErr(bundle_deploy::file_system::PathError),
 }
 
 impl Display for SourceResolveErr {
@@ -113,43 +213,156 @@ pub type SourceResolveResult = Result<SourceResolveOK, SourceResolveErr>;
 
 #[derive(CustomType, Clone, Debug)]
 pub struct InstallerBuilder {
+    metadata: application::Metadata,
+    conflict: installer::ConflictPolicy,
     sources: Vec<Source>,
     dir_sources: Vec<PathBuf>,
+    file_sources: Vec<installer::WriteFileTask>,
+    link_sources: Vec<installer::CreateLinkTask>,
+    env_sources: Vec<installer::EnvTask>,
+    env_target: bundle_deploy::env::EnvTarget,
+    attribute_rules: Vec<(glob::Pattern, Attributes)>,
 }
 
 impl InstallerBuilder {
     pub fn new() -> Self {
         Self {
+            metadata: application::Metadata::default(),
+            conflict: installer::ConflictPolicy::default(),
             sources: Vec::with_capacity(5),
             dir_sources: Vec::with_capacity(5),
+            file_sources: Vec::with_capacity(5),
+            link_sources: Vec::with_capacity(5),
+            env_sources: Vec::with_capacity(5),
+            env_target: bundle_deploy::env::EnvTarget::shell_profile(),
+            attribute_rules: Vec::new(),
         }
     }
 
+    pub fn metadata(&self) -> &application::Metadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut application::Metadata {
+        &mut self.metadata
+    }
+
+    /// Sets the conflict policy of every task that does not have its own.
+    pub fn set_conflict_policy(&mut self, policy: installer::ConflictPolicy) {
+        self.conflict = policy;
+    }
+
+    /// The file environment changes are written to unless given their own.
+    pub fn env_target(&self) -> &bundle_deploy::env::EnvTarget {
+        &self.env_target
+    }
+
+    pub fn set_env_target(&mut self, target: bundle_deploy::env::EnvTarget) {
+        self.env_target = target;
+    }
+
     pub fn add_source(&mut self, source: Source) {
         self.sources.push(source);
     }
 
+    pub fn add_dir(&mut self, path: PathBuf) {
+        self.dir_sources.push(path);
+    }
+
+    pub fn add_file(
+        &mut self,
+        content: Vec<u8>,
+        to: PathBuf,
+        conflict: Option<installer::ConflictPolicy>,
+    ) {
+        self.file_sources.push(installer::WriteFileTask::Contents {
+            content,
+            to,
+            conflict,
+            attributes: Attributes::default(),
+        });
+    }
+
+    pub fn add_link(
+        &mut self,
+        from: PathBuf,
+        to: PathBuf,
+        link_type: installer::LinkType,
+        conflict: Option<installer::ConflictPolicy>,
+    ) {
+        let mut task = installer::CreateLinkTask::new(from, to, link_type);
+        task.set_conflict(conflict);
+        self.link_sources.push(task);
+    }
+
+    pub fn add_env(
+        &mut self,
+        target: bundle_deploy::env::EnvTarget,
+        change: bundle_deploy::env::EnvChange,
+    ) {
+        self.env_sources
+            .push(installer::EnvTask::new(target, change));
+    }
+
+    /// Gives every directory and file whose destination matches `pattern` the
+    /// fields set in `attributes`. Later rules win over earlier ones.
+    pub fn set_attributes(&mut self, pattern: glob::Pattern, attributes: Attributes) {
+        self.attribute_rules.push((pattern, attributes));
+    }
+
     pub fn build(self) -> BuildResult {
+        self.build_with_progress(&progress::Silent)
+    }
+
+    /// Builds the installer, reporting progress while the sources are resolved.
+    pub fn build_with_progress(self, progress: &dyn Progress) -> BuildResult {
         let mut dir_tasks = Vec::with_capacity(self.dir_sources.len() + self.sources.len() * 32);
         let mut file_tasks = Vec::with_capacity(128);
-        let mut link_tasks = Vec::with_capacity(5);
-        let mut env_tasks = Vec::with_capacity(5);
+        let mut link_tasks = self.link_sources;
+        let env_tasks = self.env_sources;
         for dir_source in self.dir_sources {
             dir_tasks.push(installer::CreateDirectoryTask::new(dir_source));
         }
+        if !self.sources.is_empty() {
+            progress.event(&ProgressEvent::Phase(Phase::Resolving));
+        }
         for source in self.sources {
-            let result = source.resolve();
-            let (mut source_dir_tasks, mut source_file_tasks) = match result {
-                Ok(r) => (r.dir_tasks, r.file_tasks),
-                Err(e) => return Err(BuildError::SourceError(e)),
+            let result = source.resolve_with_progress(progress);
+            let (mut source_dir_tasks, mut source_file_tasks, mut source_link_tasks) = match result
+            {
+                Ok(r) => (r.dir_tasks, r.file_tasks, r.link_tasks),
+                Err(e) => {
+                    progress.event(&ProgressEvent::Error(&e));
+                    return Err(BuildError::SourceError(e));
+                }
             };
             dir_tasks.append(&mut source_dir_tasks);
             file_tasks.append(&mut source_file_tasks);
+            link_tasks.append(&mut source_link_tasks);
+        }
+        file_tasks.extend(self.file_sources);
+        for (pattern, attributes) in &self.attribute_rules {
+            for task in &mut dir_tasks {
+                if pattern.matches_path(task.path()) {
+                    task.attributes_mut().merge(attributes);
+                }
+            }
+            for task in &mut file_tasks {
+                if pattern.matches_path(task.to()) {
+                    task.attributes_mut().merge(attributes);
+                }
+            }
         }
-        // todo!()
-        Ok(installer::Installer::new(
-            dir_tasks, file_tasks, link_tasks, env_tasks,
-        ))
+        let mut installer =
+            installer::Installer::new(self.metadata, dir_tasks, file_tasks, link_tasks, env_tasks);
+        installer.set_conflict_policy(self.conflict);
+        Ok(installer)
+    }
+}
+
+impl Default for InstallerBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -169,3 +382,162 @@ impl Display for BuildError {
 impl std::error::Error for BuildError {}
 
 pub type BuildResult = Result<installer::Installer, BuildError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("veridian-builder-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn source(path: SourcePath) -> Source {
+        Source {
+            path,
+            destination: PathBuf::from("/dest"),
+            conflict: None,
+        }
+    }
+
+    fn pattern(pattern: &str) -> glob::Pattern {
+        glob::Pattern::new(pattern).unwrap()
+    }
+
+    /// Writes a tar archive with `demo/bin/` (mode 0o700), `demo/bin/app`,
+    /// `demo/bin/app.txt`, `demo/share/doc/readme.txt`, the symlink
+    /// `demo/bin/run -> app` and `other.txt`.
+    fn demo_archive(dir: &Path) -> PathBuf {
+        let archive = dir.join("demo.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&archive).unwrap());
+        let mut append = |path: &str, entry_type: tar::EntryType, mode: u32, data: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_path(path).unwrap();
+            header.set_entry_type(entry_type);
+            header.set_mode(mode);
+            header.set_size(data.len() as u64);
+            if entry_type == tar::EntryType::Symlink {
+                header.set_link_name("app").unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, data).unwrap();
+        };
+        append("demo/bin/", tar::EntryType::Directory, 0o700, b"");
+        append("demo/bin/app", tar::EntryType::Regular, 0o755, b"app");
+        append("demo/bin/app.txt", tar::EntryType::Regular, 0o644, b"txt");
+        append(
+            "demo/share/doc/readme.txt",
+            tar::EntryType::Regular,
+            0o644,
+            b"readme",
+        );
+        append("demo/bin/run", tar::EntryType::Symlink, 0o777, b"");
+        append("other.txt", tar::EntryType::Regular, 0o644, b"other");
+        builder.finish().unwrap();
+        archive
+    }
+
+    fn dirs(resolved: &SourceResolveOK) -> Vec<(PathBuf, Option<u32>)> {
+        resolved
+            .dir_tasks
+            .iter()
+            .map(|t| (t.path().clone(), t.attributes().mode))
+            .collect()
+    }
+
+    fn files(resolved: &SourceResolveOK) -> Vec<PathBuf> {
+        let mut files: Vec<_> = resolved.file_tasks.iter().map(|t| t.to().clone()).collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn archive_sources_resolve_entries_of_the_inner_directory() {
+        let dir = temp_dir("inner");
+        let archive = demo_archive(&dir);
+        let inner = RelativePath::new(["demo"]).unwrap();
+        let resolved = source(SourcePath::Archive(archive, inner, pattern("*")))
+            .resolve()
+            .unwrap();
+
+        assert_eq!(
+            files(&resolved),
+            vec![
+                PathBuf::from("/dest/bin/app"),
+                PathBuf::from("/dest/bin/app.txt"),
+                PathBuf::from("/dest/share/doc/readme.txt"),
+            ]
+        );
+        assert!(matches!(
+            resolved.file_tasks[0],
+            installer::WriteFileTask::FromArchive { ref entry, .. } if entry == Path::new("demo/bin/app")
+        ));
+        assert_eq!(
+            dirs(&resolved),
+            vec![
+                (PathBuf::from("/dest/bin"), Some(0o700)),
+                (PathBuf::from("/dest/share"), None),
+                (PathBuf::from("/dest/share/doc"), None),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn archive_symlinks_become_symbolic_link_tasks() {
+        let dir = temp_dir("symlink");
+        let archive = demo_archive(&dir);
+        let inner = RelativePath::new(["demo", "bin"]).unwrap();
+        let resolved = source(SourcePath::Archive(archive, inner, pattern("run")))
+            .resolve()
+            .unwrap();
+
+        assert!(resolved.file_tasks.is_empty());
+        assert!(resolved.dir_tasks.is_empty());
+        assert_eq!(resolved.link_tasks.len(), 1);
+        let link = &resolved.link_tasks[0];
+        assert_eq!(link.from(), &PathBuf::from("app"));
+        assert_eq!(link.to(), &PathBuf::from("/dest/run"));
+        assert!(matches!(link.link_type(), installer::LinkType::Symbolic));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn patterns_are_matched_relative_to_the_source_root() {
+        let dir = temp_dir("pattern");
+        let archive = demo_archive(&dir);
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("share/doc")).unwrap();
+        fs::write(root.join("bin/app"), "app").unwrap();
+        fs::write(root.join("bin/app.txt"), "txt").unwrap();
+        fs::write(root.join("share/doc/readme.txt"), "readme").unwrap();
+
+        let inner = RelativePath::new(["demo"]).unwrap();
+        let from_archive = source(SourcePath::Archive(archive, inner, pattern("*/*.txt")))
+            .resolve()
+            .unwrap();
+        let from_disk = source(SourcePath::Disk(root, pattern("*/*.txt")))
+            .resolve()
+            .unwrap();
+
+        let expected = vec![
+            PathBuf::from("/dest/bin/app.txt"),
+            PathBuf::from("/dest/share/doc/readme.txt"),
+        ];
+        assert_eq!(files(&from_archive), expected);
+        assert_eq!(files(&from_disk), expected);
+        // Neither matches the pattern, but both hold files that do.
+        let parents = vec![
+            (PathBuf::from("/dest/bin"), None),
+            (PathBuf::from("/dest/share"), None),
+            (PathBuf::from("/dest/share/doc"), None),
+        ];
+        assert_eq!(dirs(&from_disk), parents);
+        assert_eq!(dirs(&from_archive), parents);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
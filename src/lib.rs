@@ -4,4 +4,8 @@ pub mod database;
 pub mod dir_path;
 pub mod installer;
 pub mod installer_builder;
+pub mod plan;
+pub mod preflight;
+pub mod progress;
 pub mod recorder;
+pub mod script;
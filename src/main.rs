@@ -1,52 +1,831 @@
-use clap::Parser;
-use rhai::Engine;
+use clap::{Parser, Subcommand};
+use std::cell::LazyCell;
 use std::fs;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 use veridian_manager::*;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
-    /// Path to a script file
-    #[arg(short, long)]
-    script: PathBuf,
+    /// Override a configuration value, as KEY=VALUE with a dotted KEY such
+    /// as profiles.personal.default-install-path
+    #[arg(short, long = "config", value_name = "KEY=VALUE", global = true)]
+    config: Vec<String>,
+    /// Profile to work in instead of the configured default-profile. Each
+    /// profile keeps its own record of installed applications
+    #[arg(short, long, global = true)]
+    profile: Option<String>,
+    /// Keep the configuration and the databases in DIR instead of the user's
+    /// directories, and install into DIR/<profile> unless the configuration
+    /// says otherwise. Defaults to VERIDIAN_HOME, or to the directory of the
+    /// executable when a veridian-manager.portable file is next to it
+    #[arg(long, value_name = "DIR", global = true)]
+    root: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Install an application from a script file
+    Install {
+        /// Path to a script file
+        script: PathBuf,
+        /// Print the install plan instead of installing
+        #[arg(long)]
+        dry_run: bool,
+        /// Write into a staging directory first and move it into place at the end
+        #[arg(long)]
+        staged: bool,
+        /// Number of files written at the same time
+        #[arg(short, long)]
+        jobs: Option<usize>,
+        /// Install even over files that belong to another application, which
+        /// then stop belonging to it
+        #[arg(long)]
+        force: bool,
+        /// What to do with existing destinations of tasks without their own
+        /// policy: fail, skip, backup or overwrite. Defaults to the script's
+        /// set_conflict_policy, or overwrite
+        #[arg(long)]
+        on_conflict: Option<installer::ConflictPolicy>,
+    },
+    /// Print what installing a script would do, without touching the disk
+    Plan {
+        /// Path to a script file
+        script: PathBuf,
+    },
+    /// Uninstall an installed application
+    Uninstall {
+        /// Id or name of the application
+        application: String,
+    },
+    /// List installed applications
+    List,
+    /// Show which applications installed a path
+    Owns { path: PathBuf },
+    /// Show what an installed application has written
+    Info {
+        /// Id or name of the application
+        application: String,
+        /// Print the application and its recorder as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the files of an installed application against their recorded state
+    Verify {
+        /// Id or name of the application
+        application: String,
+    },
+    /// Re-run the install script of an application, restoring only what is
+    /// missing or modified
+    Repair {
+        /// Id or name of the application
+        application: String,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print the merged configuration and where each value comes from
+    Show,
 }
 
 fn main() {
     let args = Args::parse();
-    let config = match fs::read_to_string(dir_path::config().join("config.toml")) {
-        Ok(contents) => match config::Config::from_toml(contents.as_str()) {
+    if let Some(root) = args.root {
+        dir_path::set_root(root).expect("directories are chosen before they are used");
+    }
+    let mut loader = config::ConfigLoader::standard();
+    for assignment in args.config {
+        loader.add_override(assignment);
+    }
+    let loaded = match loader.load() {
+        Ok(l) => l,
+        Err(e) => occur_error("Config Error", e),
+    };
+    for warning in &loaded.warnings {
+        eprintln!("Config Warning:");
+        eprintln!("{}\n", warning);
+    }
+    let config = &loaded.config;
+    let profile_name = args.profile.as_deref().unwrap_or(&config.default_profile);
+    let profile = match config.profile(profile_name) {
+        Ok(p) => p,
+        Err(e) => occur_error("Config Error", e),
+    };
+    // Opened on first use, so that commands without it touch nothing on disk.
+    let database = LazyCell::new(|| {
+        let connection = match open_database(profile) {
             Ok(c) => c,
-            Err(e) => occur_error("Config File Parse Error", e),
-        },
-        Err(_) => {
-            let c = config::Config::default();
-            fs::write(
-                dir_path::config().join("config.toml"),
-                toml::to_string(&c).unwrap(),
-            )
-            .unwrap();
-            c
+            Err(e) => occur_error("Database Error", e),
+        };
+        match database::Database::new(connection) {
+            Ok(d) => d,
+            Err(e) => occur_error("Database Error", e),
         }
-    };
-    let database = database::Database::new(
-        sqlite::Connection::open_thread_safe(dir_path::data().join("database.sqlite")).unwrap(),
-    );
-    let builder = match create_builder_from_script(args.script.as_path()) {
+    });
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    match args.command {
+        Command::Install {
+            script,
+            dry_run: true,
+            ..
+        }
+        | Command::Plan { script } => {
+            let installer = build_installer(profile, script, &progress::Silent);
+            print_plan(&plan::Plan::new(&installer));
+            if let Some(database) = open_database_read_only(profile) {
+                let name = &installer.metadata().name;
+                let owned = match database.foreign_paths(name, installer.destinations()) {
+                    Ok(o) => o,
+                    Err(e) => occur_error("Database Error", e),
+                };
+                let mut owned: Vec<_> = owned.into_iter().collect();
+                owned.sort();
+                for (path, id) in owned {
+                    println!(
+                        "{} belongs to {} ({})",
+                        path.display(),
+                        application_name(&database, id),
+                        id
+                    );
+                }
+            }
+        }
+        Command::Install {
+            script,
+            staged,
+            jobs,
+            force,
+            on_conflict,
+            ..
+        } => {
+            check_privileges(profile);
+            let progress_bar = Arc::new(ProgressBar::new());
+            let mut installer = build_installer(profile, script, &*progress_bar);
+            progress_bar.clear();
+            if !force {
+                let name = &installer.metadata().name;
+                match database.foreign_paths(name, installer.destinations()) {
+                    Ok(owned) => installer.set_owned_paths(owned),
+                    Err(e) => occur_error("Database Error", e),
+                }
+            }
+            if let Some(jobs) = jobs {
+                installer.set_concurrency(jobs);
+            }
+            if let Some(policy) = on_conflict {
+                installer.set_conflict_policy(policy);
+            }
+            installer.set_progress(progress_bar.clone());
+            let res = if staged {
+                runtime.block_on(installer.install_staged(&profile.default_install_path))
+            } else {
+                runtime.block_on(installer.install())
+            };
+            progress_bar.clear();
+            match res {
+                Ok(application) => {
+                    let res = if force {
+                        database.add_application_taking_over(&application)
+                    } else {
+                        database.add_application(&application)
+                    };
+                    if let Err(e) = res {
+                        let recorder = application.into_recorder();
+                        if let Err(rollback_err) = runtime.block_on(recorder.rollback()) {
+                            print_rollback_failures(&rollback_err);
+                        }
+                        occur_error("Database Error", e);
+                    }
+                    let metadata = application.metadata();
+                    println!("Installed {} ({})", metadata.name, application.id());
+                }
+                Err((_, installer::InstallErr::Preflight(e))) => print_preflight_failure(&e),
+                Err((_, installer::InstallErr::Owned(owned))) => {
+                    print_ownership_conflict(&database, &owned)
+                }
+                Err((recorder, e)) => {
+                    if let Err(rollback_err) = runtime.block_on(recorder.rollback()) {
+                        print_rollback_failures(&rollback_err);
+                    }
+                    occur_error("Install Error", e)
+                }
+            }
+        }
+        Command::Uninstall { application } => {
+            check_privileges(profile);
+            let application = find_application(&database, &application);
+            let id = application.id();
+            let application_name = application.metadata().name.clone();
+            let shared = match database.shared_paths(id) {
+                Ok(s) => s,
+                Err(e) => occur_error("Database Error", e),
+            };
+            let mut recorder = application.into_recorder();
+            recorder.forget(&shared);
+            if let Err(e) = runtime.block_on(recorder.rollback()) {
+                print_rollback_failures(&e);
+                occur_error("Uninstall Error", e);
+            }
+            if let Err(e) = database.remove_application(id) {
+                occur_error("Database Error", e);
+            }
+            println!("Uninstalled {} ({})", application_name, id);
+        }
+        Command::List => {
+            let applications = match database.list_applications() {
+                Ok(a) => a,
+                Err(e) => occur_error("Database Error", e),
+            };
+            for (id, metadata) in applications {
+                println!(
+                    "{}  {}  {}  {}  {}",
+                    id,
+                    metadata.name,
+                    metadata.version.as_deref().unwrap_or("-"),
+                    metadata.profile,
+                    metadata.installed_at_rfc3339(),
+                );
+            }
+        }
+        Command::Owns { path } => {
+            let owners = match query_owners(&database, &path) {
+                Ok(o) => o,
+                Err(e) => print_query_error(e),
+            };
+            for (id, kind) in owners {
+                println!("{}  {}  {}", id, application_name(&database, id), kind);
+            }
+        }
+        Command::Info { application, json } => {
+            let application = find_application(&database, &application);
+            let metadata = application.metadata();
+            if json {
+                println!("{:#}", info_json(&application));
+                return;
+            }
+            println!("Id:           {}", application.id());
+            println!("Name:         {}", metadata.name);
+            println!(
+                "Version:      {}",
+                metadata.version.as_deref().unwrap_or("-")
+            );
+            println!(
+                "Description:  {}",
+                metadata.description.as_deref().unwrap_or("-")
+            );
+            println!(
+                "Publisher:    {}",
+                metadata.publisher.as_deref().unwrap_or("-")
+            );
+            println!(
+                "Homepage:     {}",
+                metadata.homepage.as_deref().unwrap_or("-")
+            );
+            println!("Profile:      {}", metadata.profile);
+            println!("Installed at: {}", metadata.installed_at_rfc3339());
+            println!("Script:       {}", metadata.script.display());
+            println!("{:#?}", application.recorder());
+        }
+        Command::Verify { application } => {
+            let application = find_application(&database, &application);
+            let recorder = application.recorder();
+            let issues = runtime.block_on(recorder.verify());
+            for issue in &issues {
+                match issue {
+                    recorder::VerifyIssue::Missing(path) => {
+                        println!("missing      {}", path.display())
+                    }
+                    recorder::VerifyIssue::Unreadable(path, e) => {
+                        println!("unreadable   {} ({})", path.display(), e)
+                    }
+                    recorder::VerifyIssue::Modified(path) => {
+                        println!("modified     {}", path.display())
+                    }
+                    recorder::VerifyIssue::PermissionsChanged {
+                        path,
+                        expected,
+                        actual,
+                    } => println!(
+                        "permissions  {} ({} -> {})",
+                        path.display(),
+                        format_mode(*expected),
+                        format_mode(*actual)
+                    ),
+                    recorder::VerifyIssue::OwnerChanged {
+                        path,
+                        expected,
+                        actual,
+                    } => println!(
+                        "owner        {} ({} -> {})",
+                        path.display(),
+                        format_owner(*expected),
+                        format_owner(*actual)
+                    ),
+                }
+            }
+            let unchecked = recorder
+                .files()
+                .iter()
+                .filter(|f| f.state().is_none())
+                .count();
+            if unchecked > 0 {
+                println!(
+                    "{} files were recorded without a state and were only checked for existence",
+                    unchecked
+                );
+            }
+            if !issues.is_empty() {
+                eprintln!(
+                    "{} of {} paths failed verification",
+                    issues.len(),
+                    recorder.directories().len() + recorder.files().len()
+                );
+                std::process::exit(1);
+            }
+            println!(
+                "{} directories and {} files verified",
+                recorder.directories().len(),
+                recorder.files().len()
+            );
+        }
+        Command::Repair { application } => {
+            check_privileges(profile);
+            let application = find_application(&database, &application);
+            let id = application.id();
+            let script = application.metadata().script.clone();
+            if script.as_os_str().is_empty() {
+                eprintln!("Repair Error:");
+                eprintln!("no install script was recorded for this application\n");
+                std::process::exit(1);
+            }
+            let progress_bar = Arc::new(ProgressBar::new());
+            let mut installer = build_installer(profile, script, &*progress_bar);
+            installer.set_progress(progress_bar.clone());
+            let mut recorder = application.into_recorder();
+            let res = runtime.block_on(installer.repair(&mut recorder));
+            progress_bar.clear();
+            if let Err(e) = database.update_recorder(id, &recorder) {
+                occur_error("Database Error", e);
+            }
+            let repaired = match res {
+                Ok(r) => r,
+                Err(installer::InstallErr::Preflight(e)) => print_preflight_failure(&e),
+                Err(e) => occur_error("Repair Error", e),
+            };
+            for path in repaired
+                .directories
+                .iter()
+                .chain(&repaired.files)
+                .chain(&repaired.links)
+            {
+                println!("restored     {}", path.display());
+            }
+            for path in &repaired.unrepairable {
+                println!("unrepairable {}", path.display());
+            }
+            println!(
+                "Restored {} directories, {} files and {} links",
+                repaired.directories.len(),
+                repaired.files.len(),
+                repaired.links.len()
+            );
+            if !repaired.unrepairable.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Command::Config {
+            command: ConfigCommand::Show,
+        } => print_config(&loaded),
+    }
+}
+
+/// Exits if `profile` requires root and the process does not run as root.
+/// Outside Unix this is not checked, see `bundle_deploy::env::is_root`.
+fn check_privileges(profile: &config::Profile) {
+    if profile.requires_root && !bundle_deploy::env::is_root() {
+        eprintln!("Permission Error:");
+        eprintln!(
+            "profile `{}` requires root, run again as root or choose another profile with --profile\n",
+            profile.name
+        );
+        std::process::exit(1);
+    }
+}
+
+fn open_database(profile: &config::Profile) -> Result<sqlite::ConnectionThreadSafe, sqlite::Error> {
+    open_database_in(dir_path::data(), &profile.name)
+}
+
+/// Opens the database of the profile named `profile` in `data`. Applications
+/// installed before databases were kept per profile were all installed into
+/// `personal`, so its database takes over the old shared one.
+fn open_database_in(
+    data: &Path,
+    profile: &str,
+) -> Result<sqlite::ConnectionThreadSafe, sqlite::Error> {
+    let path = data.join(dir_path::database_file_name(profile));
+    let legacy = data.join(dir_path::LEGACY_DATABASE_FILE_NAME);
+    fs::create_dir_all(data).map_err(|e| sqlite::Error {
+        code: None,
+        message: Some(format!("cannot create {}: {}", data.display(), e)),
+    })?;
+    if profile == "personal" && !path.exists() && legacy.exists() {
+        fs::rename(&legacy, &path).map_err(|e| sqlite::Error {
+            code: None,
+            message: Some(format!("cannot move {}: {}", legacy.display(), e)),
+        })?;
+    }
+    sqlite::Connection::open_thread_safe(path)
+}
+
+fn build_installer(
+    profile: &config::Profile,
+    script: PathBuf,
+    progress: &dyn progress::Progress,
+) -> installer::Installer {
+    let mut builder = match script::create_builder_from_script(script.as_path(), profile) {
         Ok(b) => b,
         Err(e) => occur_error("Script Error", e),
     };
-    println!("{:#?}", builder);
+    let metadata = builder.metadata_mut();
+    if metadata.name.is_empty() {
+        metadata.name = script
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    }
+    metadata.profile = profile.name.clone();
+    metadata.script = fs::canonicalize(&script).unwrap_or(script);
+    match builder.build_with_progress(progress) {
+        Ok(i) => i,
+        Err(e) => occur_error("Build Error", e),
+    }
+}
+
+/// Draws the install progress on a single line of stderr, if it is a terminal.
+struct ProgressBar {
+    enabled: bool,
+    state: Mutex<ProgressBarState>,
+}
+
+#[derive(Default)]
+struct ProgressBarState {
+    phase: Option<progress::Phase>,
+    scanned: usize,
+    files: usize,
+    copied: u64,
+    total: u64,
+    drawn_at: Option<Instant>,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 30;
+    const REDRAW_INTERVAL: Duration = Duration::from_millis(50);
+
+    fn new() -> Self {
+        Self {
+            enabled: std::io::stderr().is_terminal(),
+            state: Mutex::new(ProgressBarState::default()),
+        }
+    }
+
+    fn clear(&self) {
+        if self.enabled {
+            eprint!("\r\x1b[2K");
+        }
+    }
+
+    fn draw(&self, state: &ProgressBarState) {
+        let line = match state.phase {
+            Some(progress::Phase::Resolving) => {
+                format!("Resolving sources: {} entries", state.scanned)
+            }
+            Some(progress::Phase::WritingFiles) => {
+                let ratio = if state.total == 0 {
+                    1.0
+                } else {
+                    state.copied as f64 / state.total as f64
+                };
+                let filled = ((ratio * Self::WIDTH as f64) as usize).min(Self::WIDTH);
+                format!(
+                    "[{}{}] {:>3}%  {} / {}  {} files",
+                    "#".repeat(filled),
+                    "-".repeat(Self::WIDTH - filled),
+                    (ratio * 100.0) as u32,
+                    format_bytes(state.copied),
+                    format_bytes(state.total),
+                    state.files
+                )
+            }
+            Some(progress::Phase::Preflight) => "Checking free space and permissions".to_string(),
+            Some(progress::Phase::CreatingDirectories) => "Creating directories".to_string(),
+            Some(progress::Phase::CreatingLinks) => "Creating links".to_string(),
+            Some(progress::Phase::ApplyingEnv) => "Updating environment".to_string(),
+            Some(progress::Phase::Swapping) => "Moving staged files into place".to_string(),
+            None => return,
+        };
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+impl progress::Progress for ProgressBar {
+    fn event(&self, event: &progress::ProgressEvent<'_>) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let redraw_now = match event {
+            progress::ProgressEvent::Phase(phase) => {
+                state.phase = Some(*phase);
+                true
+            }
+            progress::ProgressEvent::Scanned { entries, .. } => {
+                state.scanned += entries;
+                false
+            }
+            progress::ProgressEvent::FileStarted { .. } => return,
+            progress::ProgressEvent::FileFinished { .. } => {
+                state.files += 1;
+                false
+            }
+            progress::ProgressEvent::Bytes { copied, total } => {
+                state.copied = *copied;
+                state.total = *total;
+                copied == total
+            }
+            progress::ProgressEvent::Error(_) => {
+                self.clear();
+                return;
+            }
+        };
+        if !redraw_now
+            && state
+                .drawn_at
+                .is_some_and(|t| t.elapsed() < Self::REDRAW_INTERVAL)
+        {
+            return;
+        }
+        state.drawn_at = Some(Instant::now());
+        self.draw(&state);
+    }
+}
+
+fn print_plan(plan: &plan::Plan) {
+    let exists = |e: bool| if e { "  [exists]" } else { "" };
+    println!("Directories ({}):", plan.directories.len());
+    for dir in &plan.directories {
+        println!("  {}{}", dir.path.display(), exists(dir.exists));
+    }
+    println!("Files ({}):", plan.files.len());
+    for file in &plan.files {
+        let source = match &file.source {
+            plan::PlannedSource::Path(path) => path.display().to_string(),
+            plan::PlannedSource::Archive { archive, entry } => {
+                format!("{}:{}", archive.display(), entry.display())
+            }
+            plan::PlannedSource::Contents => "<generated>".to_string(),
+        };
+        let size = file.size.map_or("unreadable".to_string(), format_bytes);
+        println!(
+            "  {} -> {} ({}){}",
+            source,
+            file.to.display(),
+            size,
+            exists(file.exists)
+        );
+    }
+    println!("Links ({}):", plan.links.len());
+    for link in &plan.links {
+        println!(
+            "  {} -> {} ({:?}){}",
+            link.to.display(),
+            link.from.display(),
+            link.link_type,
+            exists(link.exists)
+        );
+    }
+    println!("Env changes ({}):", plan.envs.len());
+    for env in &plan.envs {
+        match &env.line {
+            Ok(line) => println!("  {}: {}", env.path.display(), line),
+            Err(e) => println!("  {}: invalid ({})", env.path.display(), e),
+        }
+    }
+    println!("Total: {}", format_bytes(plan.total_bytes()));
+    if plan.conflicts() > 0 {
+        println!("{} destinations already exist", plan.conflicts());
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Exits after listing the destinations that belong to other applications.
+fn print_ownership_conflict(database: &database::Database, conflicts: &[(PathBuf, Uuid)]) -> ! {
+    eprintln!("Ownership Conflict:");
+    for (path, id) in conflicts {
+        eprintln!(
+            "{} belongs to {} ({})",
+            path.display(),
+            application_name(database, *id),
+            id
+        );
+    }
+    eprintln!("Use --force to install anyway and take these paths over\n");
+    std::process::exit(1);
+}
+
+/// Opens the existing database of `profile` without writing to it, or
+/// `None` if there is none yet or it still needs migrating.
+fn open_database_read_only(profile: &config::Profile) -> Option<database::Database> {
+    let path = dir_path::data().join(dir_path::database_file_name(&profile.name));
+    let legacy = dir_path::data().join(dir_path::LEGACY_DATABASE_FILE_NAME);
+    let path = if profile.name == "personal" && !path.exists() {
+        legacy
+    } else {
+        path
+    };
+    if !path.exists() {
+        return None;
+    }
+    let flags = sqlite::OpenFlags::new().with_read_only().with_full_mutex();
+    let connection = match sqlite::Connection::open_thread_safe_with_flags(path, flags) {
+        Ok(c) => c,
+        Err(e) => occur_error("Database Error", e),
+    };
+    match database::Database::open_read_only(connection) {
+        Ok(d) => Some(d),
+        Err(database::DatabaseErr::Outdated { .. }) => None,
+        Err(e) => occur_error("Database Error", e),
+    }
+}
+
+fn application_name(database: &database::Database, id: Uuid) -> String {
+    match database.get_application(id) {
+        Ok(Some(application)) => application.metadata().name.clone(),
+        Ok(None) => "-".to_string(),
+        Err(e) => occur_error("Database Error", e),
+    }
+}
+
+fn format_mode(mode: Option<u32>) -> String {
+    mode.map_or("-".to_string(), |m| format!("{:o}", m))
+}
+
+fn format_owner((uid, gid): (Option<u32>, Option<u32>)) -> String {
+    let id = |id: Option<u32>| id.map_or("-".to_string(), |id| id.to_string());
+    format!("{}:{}", id(uid), id(gid))
+}
+
+fn find_application(database: &database::Database, application: &str) -> application::Application {
+    match query_application(database, application) {
+        Ok(a) => a,
+        Err(e) => print_query_error(e),
+    }
+}
+
+/// Why a command could not find what it was asked about.
+#[derive(Debug)]
+enum QueryErr {
+    Database(database::DatabaseErr),
+    /// No application has this id or name.
+    NotInstalled(String),
+    /// The name is shared by this many applications.
+    Ambiguous(String, usize),
+    /// No application recorded this path.
+    UnknownPath(PathBuf),
+}
+
+impl From<database::DatabaseErr> for QueryErr {
+    fn from(error: database::DatabaseErr) -> Self {
+        QueryErr::Database(error)
+    }
+}
+
+/// Looks `application` up as an id, then as a name.
+fn query_application(
+    database: &database::Database,
+    application: &str,
+) -> Result<application::Application, QueryErr> {
+    if let Ok(id) = Uuid::parse_str(application)
+        && let Some(application) = database.get_application(id)?
+    {
+        return Ok(application);
+    }
+    let ids = database.find_applications(application)?;
+    match ids.as_slice() {
+        [id] => database
+            .get_application(*id)?
+            .ok_or_else(|| QueryErr::NotInstalled(application.to_string())),
+        [] => Err(QueryErr::NotInstalled(application.to_string())),
+        _ => Err(QueryErr::Ambiguous(application.to_string(), ids.len())),
+    }
 }
 
-fn create_builder_from_script(
+/// The owners of `path`, looked up as given and then canonicalized.
+fn query_owners(
+    database: &database::Database,
     path: &Path,
-) -> Result<installer_builder::InstallerBuilder, Box<rhai::EvalAltResult>> {
-    let mut engine = Engine::new();
-    engine
-        .register_type::<installer_builder::InstallerBuilder>()
-        .register_fn("InstallerBuilder", installer_builder::InstallerBuilder::new);
-    engine.eval_file::<installer_builder::InstallerBuilder>(path.to_path_buf())
+) -> Result<Vec<(Uuid, database::PathKind)>, QueryErr> {
+    let mut owners = database.owners(path)?;
+    if owners.is_empty()
+        && let Ok(canonical) = fs::canonicalize(path)
+    {
+        owners = database.owners(&canonical)?;
+    }
+    if owners.is_empty() {
+        return Err(QueryErr::UnknownPath(path.to_path_buf()));
+    }
+    Ok(owners)
+}
+
+/// What `info --json` prints.
+fn info_json(application: &application::Application) -> serde_json::Value {
+    serde_json::json!({
+        "id": application.id().to_string(),
+        "metadata": application.metadata(),
+        "recorder": application.recorder(),
+    })
+}
+
+fn print_query_error(error: QueryErr) -> ! {
+    match error {
+        QueryErr::Database(e) => occur_error("Database Error", e),
+        QueryErr::NotInstalled(application) => {
+            eprintln!("Application Not Found:");
+            eprintln!("{}\n", application);
+        }
+        QueryErr::Ambiguous(application, count) => {
+            eprintln!("Ambiguous Application Name:");
+            eprintln!(
+                "{} matches {} applications, use an id instead\n",
+                application, count
+            );
+        }
+        QueryErr::UnknownPath(path) => {
+            eprintln!("{} is not owned by any application", path.display());
+        }
+    }
+    std::process::exit(1);
+}
+
+fn print_preflight_failure(error: &preflight::PreflightErr) -> ! {
+    eprintln!("Preflight Check Failed:");
+    for shortage in &error.shortages {
+        eprintln!(
+            "not enough space on the filesystem of {}: {} needed, {} available",
+            shortage.path.display(),
+            format_bytes(shortage.required),
+            format_bytes(shortage.available)
+        );
+    }
+    for path in &error.unwritable {
+        eprintln!("cannot write to {}", path.display());
+    }
+    eprintln!("Nothing was changed\n");
+    std::process::exit(1);
+}
+
+fn print_rollback_failures(error: &recorder::RollbackErr) {
+    for failure in error.failures() {
+        eprintln!("{:?}", failure);
+    }
+}
+
+fn print_config(loaded: &config::LoadedConfig) {
+    for (key, value) in loaded.values() {
+        let source = loaded
+            .sources
+            .get(&key)
+            .unwrap_or(&config::ConfigSource::Default);
+        println!("{} = {}  # {}", key, value, source);
+    }
 }
 
 fn occur_error(title: &str, error: impl std::error::Error) -> ! {
@@ -54,3 +833,112 @@ fn occur_error(title: &str, error: impl std::error::Error) -> ! {
     eprintln!("{}\n", error);
     std::process::exit(1);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add(database: &database::Database, name: &str) {
+        let metadata = application::Metadata {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        let application =
+            application::Application::new(Uuid::new_v4(), metadata, Default::default());
+        database.add_application(&application).unwrap();
+    }
+
+    #[test]
+    fn each_profile_has_its_own_database() {
+        let data = std::env::temp_dir().join(format!("veridian-main-data-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data);
+        fs::create_dir_all(&data).unwrap();
+        let legacy = data.join(dir_path::LEGACY_DATABASE_FILE_NAME);
+        let shared =
+            database::Database::new(sqlite::Connection::open_thread_safe(&legacy).unwrap())
+                .unwrap();
+        add(&shared, "old");
+        drop(shared);
+
+        let global = database::Database::new(open_database_in(&data, "global").unwrap()).unwrap();
+        assert!(global.list_applications().unwrap().is_empty());
+        assert!(legacy.exists());
+        add(&global, "new");
+
+        let personal =
+            database::Database::new(open_database_in(&data, "personal").unwrap()).unwrap();
+        assert!(!legacy.exists());
+        assert_eq!(personal.find_applications("old").unwrap().len(), 1);
+        assert!(personal.find_applications("new").unwrap().is_empty());
+        assert!(global.find_applications("old").unwrap().is_empty());
+        fs::remove_dir_all(data).unwrap();
+    }
+
+    fn installed(name: &str, file: &str) -> (database::Database, application::Application) {
+        let connection = sqlite::Connection::open_thread_safe(":memory:").unwrap();
+        let database = database::Database::new(connection).unwrap();
+        let metadata = application::Metadata {
+            name: name.to_string(),
+            version: Some("1.0".to_string()),
+            ..Default::default()
+        };
+        let mut recorder = recorder::Recorder::default();
+        recorder.record_file(recorder::FileRecord::from(PathBuf::from(file)));
+        let application = application::Application::new(Uuid::new_v4(), metadata, recorder);
+        database.add_application(&application).unwrap();
+        (database, application)
+    }
+
+    #[test]
+    fn applications_are_found_by_id_or_name() {
+        let (database, installed) = installed("demo", "/opt/demo/app");
+        let by_name = query_application(&database, "demo").unwrap();
+        assert_eq!(by_name.id(), installed.id());
+        let by_id = query_application(&database, &installed.id().to_string()).unwrap();
+        assert_eq!(by_id.metadata().name, "demo");
+
+        let listed = database.list_applications().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].0, installed.id());
+        assert_eq!(listed[0].1.version.as_deref(), Some("1.0"));
+    }
+
+    #[test]
+    fn missing_applications_are_not_installed() {
+        let (database, _) = installed("demo", "/opt/demo/app");
+        for query in ["other".to_string(), Uuid::new_v4().to_string()] {
+            let err = query_application(&database, &query).err().unwrap();
+            assert!(
+                matches!(err, QueryErr::NotInstalled(ref q) if *q == query),
+                "{:?}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn owners_are_looked_up_by_path() {
+        let (database, installed) = installed("demo", "/opt/demo/app");
+        let owners = query_owners(&database, Path::new("/opt/demo/app")).unwrap();
+        assert_eq!(owners, vec![(installed.id(), database::PathKind::File)]);
+
+        let unknown = Path::new("/opt/demo/unknown");
+        let err = query_owners(&database, unknown).err().unwrap();
+        assert!(
+            matches!(err, QueryErr::UnknownPath(ref p) if p == unknown),
+            "{:?}",
+            err
+        );
+    }
+
+    #[test]
+    fn info_json_holds_the_metadata_and_the_recorder() {
+        let (database, installed) = installed("demo", "/opt/demo/app");
+        let application = query_application(&database, "demo").unwrap();
+        let value = info_json(&application);
+        assert_eq!(value["id"], installed.id().to_string());
+        assert_eq!(value["metadata"]["name"], "demo");
+        assert_eq!(value["metadata"]["version"], "1.0");
+        assert_eq!(value["recorder"]["file_tasks"][0]["path"], "/opt/demo/app");
+    }
+}
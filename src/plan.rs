@@ -0,0 +1,217 @@
+use crate::installer;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub struct PlannedDirectory {
+    pub path: PathBuf,
+    pub exists: bool,
+}
+
+#[derive(Debug)]
+pub enum PlannedSource {
+    Path(PathBuf),
+    Archive { archive: PathBuf, entry: PathBuf },
+    Contents,
+}
+
+#[derive(Debug)]
+pub struct PlannedFile {
+    pub source: PlannedSource,
+    pub to: PathBuf,
+    /// `None` when the source could not be read.
+    pub size: Option<u64>,
+    pub exists: bool,
+}
+
+#[derive(Debug)]
+pub struct PlannedLink {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub link_type: installer::LinkType,
+    pub exists: bool,
+}
+
+#[derive(Debug)]
+pub struct PlannedEnv {
+    pub path: PathBuf,
+    /// The exact line that would be written into the managed block.
+    pub line: io::Result<String>,
+}
+
+/// Everything an [`installer::Installer`] would do, worked out without
+/// touching the disk.
+#[derive(Debug)]
+pub struct Plan {
+    pub directories: Vec<PlannedDirectory>,
+    pub files: Vec<PlannedFile>,
+    pub links: Vec<PlannedLink>,
+    pub envs: Vec<PlannedEnv>,
+}
+
+impl Plan {
+    pub fn new(installer: &installer::Installer) -> Self {
+        let directories = installer
+            .dir_tasks()
+            .iter()
+            .map(|task| PlannedDirectory {
+                path: task.path().clone(),
+                exists: exists(task.path()),
+            })
+            .collect();
+        let files = installer
+            .file_tasks()
+            .iter()
+            .map(|task| {
+                let (source, size) = match task {
+                    installer::WriteFileTask::FromPath { from, .. } => (
+                        PlannedSource::Path(from.clone()),
+                        fs::metadata(from).ok().map(|m| m.len()),
+                    ),
+                    installer::WriteFileTask::Contents { content, .. } => {
+                        (PlannedSource::Contents, Some(content.len() as u64))
+                    }
+                    installer::WriteFileTask::FromArchive {
+                        archive,
+                        entry,
+                        size,
+                        ..
+                    } => (
+                        PlannedSource::Archive {
+                            archive: archive.clone(),
+                            entry: entry.clone(),
+                        },
+                        Some(*size),
+                    ),
+                };
+                PlannedFile {
+                    source,
+                    to: task.to().clone(),
+                    size,
+                    exists: exists(task.to()),
+                }
+            })
+            .collect();
+        let links = installer
+            .link_tasks()
+            .iter()
+            .map(|task| PlannedLink {
+                from: task.from().clone(),
+                to: task.to().clone(),
+                link_type: task.link_type().clone(),
+                exists: exists(task.to()),
+            })
+            .collect();
+        let envs = installer
+            .env_tasks()
+            .iter()
+            .map(|task| PlannedEnv {
+                path: task.target().path.clone(),
+                line: task.change().render(&task.target().format),
+            })
+            .collect();
+        Self {
+            directories,
+            files,
+            links,
+            envs,
+        }
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.files.iter().filter_map(|f| f.size).sum()
+    }
+
+    /// Number of files and links whose destination is already taken.
+    pub fn conflicts(&self) -> usize {
+        self.files.iter().filter(|f| f.exists).count()
+            + self.links.iter().filter(|l| l.exists).count()
+    }
+}
+
+fn exists(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application;
+    use bundle_deploy::env::{EnvChange, EnvFileFormat, EnvTarget};
+    use bundle_deploy::file_system::Attributes;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("veridian-plan-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn plans_are_worked_out_without_touching_the_disk() {
+        let dir = temp_dir("untouched");
+        fs::create_dir_all(dir.join("existing")).unwrap();
+        fs::write(dir.join("source"), "12345").unwrap();
+        fs::write(dir.join("existing/taken"), "taken").unwrap();
+        let env_file = dir.join("env.sh");
+        let installer = installer::Installer::new(
+            application::Metadata::default(),
+            vec![
+                installer::CreateDirectoryTask::new(dir.join("existing")),
+                installer::CreateDirectoryTask::new(dir.join("new")),
+            ],
+            vec![
+                installer::WriteFileTask::FromPath {
+                    from: dir.join("source"),
+                    to: dir.join("new/copied"),
+                    conflict: None,
+                    attributes: Attributes::default(),
+                },
+                installer::WriteFileTask::Contents {
+                    content: b"abc".to_vec(),
+                    to: dir.join("existing/taken"),
+                    conflict: None,
+                    attributes: Attributes::default(),
+                },
+                installer::WriteFileTask::FromPath {
+                    from: dir.join("missing"),
+                    to: dir.join("new/missing"),
+                    conflict: None,
+                    attributes: Attributes::default(),
+                },
+            ],
+            vec![installer::CreateLinkTask::new(
+                dir.join("new/copied"),
+                dir.join("existing/link"),
+                installer::LinkType::Symbolic,
+            )],
+            vec![installer::EnvTask::new(
+                EnvTarget::new(env_file.clone(), EnvFileFormat::Shell),
+                EnvChange::PrependPath(PathBuf::from("/opt/demo")),
+            )],
+        );
+
+        let plan = Plan::new(&installer);
+        let directories: Vec<_> = plan.directories.iter().map(|d| d.exists).collect();
+        assert_eq!(directories, vec![true, false]);
+        let files: Vec<_> = plan.files.iter().map(|f| (f.size, f.exists)).collect();
+        assert_eq!(
+            files,
+            vec![(Some(5), false), (Some(3), true), (None, false)]
+        );
+        assert!(matches!(plan.files[1].source, PlannedSource::Contents));
+        assert_eq!(plan.total_bytes(), 8);
+        assert!(!plan.links[0].exists);
+        assert_eq!(plan.conflicts(), 1);
+        assert_eq!(
+            plan.envs[0].line.as_ref().unwrap(),
+            "export PATH=\"/opt/demo:$PATH\""
+        );
+        assert!(!dir.join("new").exists());
+        assert!(!env_file.exists());
+        assert_eq!(fs::read(dir.join("existing/taken")).unwrap(), b"taken");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+use crate::installer;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// A filesystem without enough free space for the files written to it.
+#[derive(Debug)]
+pub struct SpaceShortage {
+    /// An existing directory on the filesystem.
+    pub path: PathBuf,
+    pub required: u64,
+    pub available: u64,
+}
+
+#[derive(Debug)]
+pub struct PreflightErr {
+    pub shortages: Vec<SpaceShortage>,
+    /// Directories that entries would be created in, or existing environment
+    /// files, that the current user cannot write to.
+    pub unwritable: Vec<PathBuf>,
+}
+
+impl Display for PreflightErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+impl std::error::Error for PreflightErr {}
+
+/// Checks, before anything is written, that every destination of `installer`
+/// can be created, that its environment files can be written, and that each
+/// filesystem has room for the files written to it. `extra_dirs` are
+/// directories the install creates entries in besides the destinations, such
+/// as the root of a staged install.
+///
+/// Space is counted without the files that would be replaced, and is not
+/// checked on platforms that cannot report it.
+pub async fn check(
+    installer: &installer::Installer,
+    extra_dirs: Vec<PathBuf>,
+) -> Result<(), installer::InstallErr> {
+    let mut files = Vec::with_capacity(installer.file_tasks().len());
+    for task in installer.file_tasks() {
+        files.push((task.to().clone(), task.size().await.unwrap_or(0)));
+    }
+    let mut destinations = extra_dirs;
+    for task in installer.dir_tasks() {
+        if bundle_deploy::file_system::metadata(task.path())
+            .await
+            .is_ok_and(|m| m.is_dir())
+        {
+            continue;
+        }
+        destinations.push(task.path().clone());
+    }
+    destinations.extend(installer.destinations().cloned());
+    let env_files: Vec<_> = installer
+        .env_tasks()
+        .iter()
+        .map(|task| task.target().path.clone())
+        .collect();
+    tokio::task::spawn_blocking(move || check_blocking(&files, &destinations, &env_files))
+        .await
+        .map_err(installer::InstallErr::Worker)?
+        .map_err(installer::InstallErr::Preflight)
+}
+
+fn check_blocking(
+    files: &[(PathBuf, u64)],
+    destinations: &[PathBuf],
+    env_files: &[PathBuf],
+) -> Result<(), PreflightErr> {
+    let mut unwritable = BTreeSet::new();
+    let mut checked = BTreeSet::new();
+    for path in env_files {
+        // Environment files are rewritten in place rather than replaced.
+        if path.exists() {
+            if std::fs::OpenOptions::new().append(true).open(path).is_err() {
+                unwritable.insert(path.clone());
+            }
+            continue;
+        }
+        let Some(dir) = parent_on_disk(path) else {
+            continue;
+        };
+        if checked.insert(dir.to_path_buf())
+            && !(dir.is_dir() && bundle_deploy::file_system::is_writable_dir(dir))
+        {
+            unwritable.insert(dir.to_path_buf());
+        }
+    }
+    for destination in destinations {
+        let Some(dir) = parent_on_disk(destination) else {
+            continue;
+        };
+        if checked.insert(dir.to_path_buf())
+            && !(dir.is_dir() && bundle_deploy::file_system::is_writable_dir(dir))
+        {
+            unwritable.insert(dir.to_path_buf());
+        }
+    }
+    let mut filesystems = HashMap::<u64, (PathBuf, u64)>::new();
+    for (to, size) in files {
+        let Some(dir) = parent_on_disk(to) else {
+            continue;
+        };
+        let Ok(id) = bundle_deploy::file_system::filesystem_id(dir) else {
+            continue;
+        };
+        filesystems
+            .entry(id)
+            .or_insert_with(|| (dir.to_path_buf(), 0))
+            .1 += size;
+    }
+    let mut shortages = Vec::new();
+    for (path, required) in filesystems.into_values() {
+        if let Ok(available) = bundle_deploy::file_system::available_space(&path)
+            && available < required
+        {
+            shortages.push(SpaceShortage {
+                path,
+                required,
+                available,
+            });
+        }
+    }
+    shortages.sort_by(|a, b| a.path.cmp(&b.path));
+    if shortages.is_empty() && unwritable.is_empty() {
+        Ok(())
+    } else {
+        Err(PreflightErr {
+            shortages,
+            unwritable: unwritable.into_iter().collect(),
+        })
+    }
+}
+
+/// The existing directory `path` would be created in.
+fn parent_on_disk(path: &Path) -> Option<&Path> {
+    bundle_deploy::file_system::existing_ancestor(path.parent()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application;
+    use bundle_deploy::file_system::Attributes;
+
+    #[test]
+    fn destinations_under_a_file_are_unwritable() {
+        let dir = std::env::temp_dir().join(format!("veridian-preflight-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        std::fs::write(&file, "in the way").unwrap();
+        let installer = installer::Installer::new(
+            application::Metadata::default(),
+            vec![installer::CreateDirectoryTask::new(file.join("sub"))],
+            vec![installer::WriteFileTask::Contents {
+                content: b"demo".to_vec(),
+                to: dir.join("ok"),
+                conflict: None,
+                attributes: Attributes::default(),
+            }],
+            Vec::new(),
+            Vec::new(),
+        );
+
+        let err = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(check(&installer, Vec::new()))
+            .unwrap_err();
+        match err {
+            installer::InstallErr::Preflight(e) => {
+                assert_eq!(e.unwritable, vec![file]);
+                assert!(e.shortages.is_empty());
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn files_larger_than_the_free_space_are_refused() {
+        let dir = std::env::temp_dir();
+        let files = [(dir.join("veridian-preflight-huge"), u64::MAX / 2)];
+        let err = check_blocking(&files, &[], &[]).unwrap_err();
+        let [shortage] = &err.shortages[..] else {
+            panic!("expected one shortage: {:?}", err);
+        };
+        assert_eq!(shortage.required, u64::MAX / 2);
+        assert!(shortage.available < shortage.required);
+        assert!(err.unwritable.is_empty());
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Resolving,
+    /// Checking free space and permissions before anything is written.
+    Preflight,
+    CreatingDirectories,
+    WritingFiles,
+    CreatingLinks,
+    ApplyingEnv,
+    /// Moving a staged install into place.
+    Swapping,
+}
+
+#[derive(Debug)]
+pub enum ProgressEvent<'a> {
+    Phase(Phase),
+    /// A directory of a disk source or a whole archive has been read.
+    Scanned {
+        path: &'a Path,
+        entries: usize,
+    },
+    FileStarted {
+        to: &'a Path,
+    },
+    FileFinished {
+        to: &'a Path,
+        bytes: u64,
+    },
+    /// Bytes written so far against the total of every file task. Sent once
+    /// with `copied == 0` when writing starts, then after every file.
+    Bytes {
+        copied: u64,
+        total: u64,
+    },
+    Error(&'a dyn std::error::Error),
+}
+
+/// Receives progress events from `Source::resolve_with_progress` and the
+/// `Installer`.
+///
+/// Files are written by several workers, so events may arrive from any thread.
+pub trait Progress: Send + Sync {
+    fn event(&self, event: &ProgressEvent<'_>);
+}
+
+impl<F> Progress for F
+where
+    F: Fn(&ProgressEvent<'_>) + Send + Sync,
+{
+    fn event(&self, event: &ProgressEvent<'_>) {
+        self(event)
+    }
+}
+
+/// Ignores every event.
+pub struct Silent;
+
+impl Progress for Silent {
+    fn event(&self, _: &ProgressEvent<'_>) {}
+}
@@ -1,42 +1,265 @@
+use bundle_deploy::file_system::Attributes;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs::Metadata;
+use std::io;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize)]
-pub struct DirectoryRecord(PathBuf);
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DirectoryRecord {
+    path: PathBuf,
+    /// Mode and ownership the directory was given, `None` where it was left
+    /// with the defaults.
+    mode: Option<u32>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+}
+
+impl DirectoryRecord {
+    pub fn new(path: PathBuf, attributes: &Attributes) -> Self {
+        Self {
+            path,
+            mode: attributes.mode,
+            uid: attributes.uid,
+            gid: attributes.gid,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        Attributes {
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+        }
+    }
+}
 
 impl From<PathBuf> for DirectoryRecord {
     fn from(path: PathBuf) -> Self {
-        DirectoryRecord(path)
+        Self::new(path, &Attributes::default())
+    }
+}
+
+/// What a file looked like right after it was written.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    /// Lowercase hex SHA-256 of the contents.
+    pub sha256: String,
+    /// Permission bits, `None` where the platform has none.
+    pub mode: Option<u32>,
+    /// Owner and group ids, `None` where the platform has none.
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FileState {
+    pub fn attributes(&self) -> Attributes {
+        Attributes {
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+        }
+    }
+
+    /// Reads and hashes the file at `path`.
+    pub async fn read(path: &Path) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let mut file = std::fs::File::open(&path)?;
+            let metadata = file.metadata()?;
+            let mut hash = ContentHash::default();
+            io::copy(&mut file, &mut hash)?;
+            Ok(Self::new(hash, &metadata))
+        })
+        .await
+        .map_err(io::Error::other)?
+    }
+
+    /// The state of the file at `path` that was just written with the
+    /// contents hashed into `hash`, without reading it back.
+    pub async fn written(path: &Path, hash: ContentHash) -> io::Result<Self> {
+        let metadata = bundle_deploy::file_system::metadata(path).await?;
+        Ok(Self::new(hash, &metadata))
+    }
+
+    fn new(hash: ContentHash, metadata: &Metadata) -> Self {
+        let (uid, gid) = owner(metadata);
+        Self {
+            size: hash.size,
+            sha256: hash
+                .hasher
+                .finalize()
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect(),
+            mode: mode(metadata),
+            uid,
+            gid,
+        }
+    }
+}
+
+/// The size and SHA-256 of contents, fed as they stream past.
+#[derive(Debug, Clone, Default)]
+pub struct ContentHash {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl ContentHash {
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct FileRecord(PathBuf);
+impl io::Write for ContentHash {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode(_: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn owner(metadata: &Metadata) -> (Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner(_: &Metadata) -> (Option<u32>, Option<u32>) {
+    (None, None)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRecord {
+    path: PathBuf,
+    /// `None` for files recorded before states were captured.
+    state: Option<FileState>,
+}
+
+impl FileRecord {
+    pub fn new(path: PathBuf, state: FileState) -> Self {
+        Self {
+            path,
+            state: Some(state),
+        }
+    }
+
+    /// Records the file at `path` together with its current state.
+    pub async fn capture(path: PathBuf) -> io::Result<Self> {
+        let state = FileState::read(&path).await?;
+        Ok(Self::new(path, state))
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn state(&self) -> Option<&FileState> {
+        self.state.as_ref()
+    }
+}
 
 impl From<PathBuf> for FileRecord {
     fn from(path: PathBuf) -> Self {
-        FileRecord(path)
+        FileRecord { path, state: None }
     }
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LinkRecord(PathBuf);
 
+impl LinkRecord {
+    pub fn path(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
 impl From<PathBuf> for LinkRecord {
     fn from(path: PathBuf) -> Self {
         LinkRecord(path)
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct EnvRecord {}
+/// A line written into the managed block of an environment file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvRecord {
+    path: PathBuf,
+    line: String,
+    /// Whether writing the line created the file, which only then is removed
+    /// once nothing else is left in it.
+    created: bool,
+}
 
-#[derive(Serialize, Deserialize)]
+impl EnvRecord {
+    pub fn new(path: PathBuf, line: String, created: bool) -> Self {
+        Self {
+            path,
+            line,
+            created,
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+}
+
+/// A file that was in the way of an install and moved aside.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupRecord {
+    original: PathBuf,
+    backup: PathBuf,
+}
+
+impl BackupRecord {
+    pub fn new(original: PathBuf, backup: PathBuf) -> Self {
+        Self { original, backup }
+    }
+
+    pub fn original(&self) -> &PathBuf {
+        &self.original
+    }
+
+    pub fn backup(&self) -> &PathBuf {
+        &self.backup
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Recorder {
     dir_tasks: Vec<DirectoryRecord>,
     file_tasks: Vec<FileRecord>,
     link_tasks: Vec<LinkRecord>,
     env_tasks: Vec<EnvRecord>,
+    backup_tasks: Vec<BackupRecord>,
 }
 
 impl Recorder {
@@ -46,6 +269,7 @@ impl Recorder {
             file_tasks: Vec::with_capacity(file),
             link_tasks: Vec::with_capacity(link),
             env_tasks: Vec::with_capacity(env),
+            backup_tasks: Vec::new(),
         }
     }
 
@@ -64,33 +288,868 @@ impl Recorder {
     pub fn record_env(&mut self, record: EnvRecord) {
         self.env_tasks.push(record);
     }
+
+    pub fn record_backup(&mut self, record: BackupRecord) {
+        self.backup_tasks.push(record);
+    }
+
+    pub fn directories(&self) -> &[DirectoryRecord] {
+        &self.dir_tasks
+    }
+
+    pub fn files(&self) -> &[FileRecord] {
+        &self.file_tasks
+    }
+
+    pub fn links(&self) -> &[LinkRecord] {
+        &self.link_tasks
+    }
+
+    pub fn envs(&self) -> &[EnvRecord] {
+        &self.env_tasks
+    }
+
+    pub fn backups(&self) -> &[BackupRecord] {
+        &self.backup_tasks
+    }
+
+    /// Adds the records of `other`. A file record replaces the one already
+    /// recorded for the same path, and directories and links already recorded
+    /// are not added twice.
+    pub fn merge(&mut self, other: Recorder) {
+        for record in other.dir_tasks {
+            if !self.dir_tasks.iter().any(|r| r.path == record.path) {
+                self.dir_tasks.push(record);
+            }
+        }
+        for record in other.file_tasks {
+            match self.file_tasks.iter_mut().find(|r| r.path == record.path) {
+                Some(existing) => *existing = record,
+                None => self.file_tasks.push(record),
+            }
+        }
+        for record in other.link_tasks {
+            if !self.link_tasks.iter().any(|r| r.0 == record.0) {
+                self.link_tasks.push(record);
+            }
+        }
+        self.env_tasks.extend(other.env_tasks);
+        self.backup_tasks.extend(other.backup_tasks);
+    }
+
+    /// Drops the directory, file and link records of `paths`, so that neither
+    /// rollback nor verify touches them again.
+    pub fn forget(&mut self, paths: &HashSet<PathBuf>) {
+        self.dir_tasks.retain(|r| !paths.contains(&r.path));
+        self.file_tasks.retain(|r| !paths.contains(&r.path));
+        self.link_tasks.retain(|r| !paths.contains(&r.0));
+    }
+
+    /// Drops the backups of the files at `originals` and returns them, for
+    /// backups that are no longer to be restored.
+    pub fn take_backups(&mut self, originals: &HashSet<PathBuf>) -> Vec<BackupRecord> {
+        let (taken, kept) = std::mem::take(&mut self.backup_tasks)
+            .into_iter()
+            .partition(|r| originals.contains(&r.original));
+        self.backup_tasks = kept;
+        taken
+    }
+
+    /// Rewrites the path of every directory, file, link and backup record.
+    pub fn map_paths(self, f: impl Fn(PathBuf) -> PathBuf) -> Self {
+        Self {
+            dir_tasks: self
+                .dir_tasks
+                .into_iter()
+                .map(|r| DirectoryRecord {
+                    path: f(r.path),
+                    ..r
+                })
+                .collect(),
+            file_tasks: self
+                .file_tasks
+                .into_iter()
+                .map(|r| FileRecord {
+                    path: f(r.path),
+                    state: r.state,
+                })
+                .collect(),
+            link_tasks: self
+                .link_tasks
+                .into_iter()
+                .map(|r| LinkRecord(f(r.0)))
+                .collect(),
+            env_tasks: self.env_tasks,
+            backup_tasks: self
+                .backup_tasks
+                .into_iter()
+                .map(|r| BackupRecord {
+                    original: f(r.original),
+                    backup: f(r.backup),
+                })
+                .collect(),
+        }
+    }
 }
 
 impl Recorder {
-    pub async fn rollback(self) -> Result<(), ()> {
-        todo!()
+    /// Undoes everything recorded, in the reverse order of installation.
+    ///
+    /// Files that were backed up are moved back once ours are removed.
+    /// Directories are only removed once they are empty, so anything the user
+    /// put there afterwards is left in place. Entries that are already gone are
+    /// not treated as failures.
+    pub async fn rollback(self) -> RollbackResult {
+        let mut failures = Vec::new();
+        for record in self.env_tasks.into_iter().rev() {
+            if let Err(e) =
+                bundle_deploy::env::revert(&record.path, &record.line, record.created).await
+            {
+                failures.push(RollbackFailure::RevertEnv(record.path, e));
+            }
+        }
+        for record in self.link_tasks.into_iter().rev() {
+            if let Err(e) = remove_file(&record.0).await {
+                failures.push(RollbackFailure::RemoveLink(record.0, e));
+            }
+        }
+        for record in self.file_tasks.into_iter().rev() {
+            if let Err(e) = remove_file(&record.path).await {
+                failures.push(RollbackFailure::RemoveFile(record.path, e));
+            }
+        }
+        for record in self.backup_tasks.into_iter().rev() {
+            let res = bundle_deploy::file_system::rename(&record.backup, &record.original).await;
+            if let Err(e) = res
+                && e.kind() != io::ErrorKind::NotFound
+            {
+                failures.push(RollbackFailure::RestoreBackup(record.original, e));
+            }
+        }
+        for record in self.dir_tasks.into_iter().rev() {
+            match bundle_deploy::file_system::remove_dir(&record.path).await {
+                Ok(_) => {}
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::NotFound | io::ErrorKind::DirectoryNotEmpty
+                    ) => {}
+                Err(e) => failures.push(RollbackFailure::RemoveDirectory(record.path, e)),
+            }
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(RollbackErr(failures))
+        }
     }
 }
 
 impl Recorder {
-    pub fn to_binary(&self) -> Vec<u8> {
-        bincode::serde::encode_to_vec(self, bincode::config::standard()).unwrap()
+    /// Compares every recorded file with its state at install time, and every
+    /// recorded directory with the mode and ownership it was given.
+    ///
+    /// Files recorded without a state can only be checked for existence.
+    pub async fn verify(&self) -> Vec<VerifyIssue> {
+        let mut issues = Vec::new();
+        for record in &self.dir_tasks {
+            let path = record.path.clone();
+            match bundle_deploy::file_system::metadata(&record.path).await {
+                Ok(metadata) => {
+                    let (uid, gid) = owner(&metadata);
+                    let actual = Attributes {
+                        mode: mode(&metadata),
+                        uid,
+                        gid,
+                    };
+                    issues.extend(compare_attributes(path, &record.attributes(), &actual));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    issues.push(VerifyIssue::Missing(path))
+                }
+                Err(e) => issues.push(VerifyIssue::Unreadable(path, e)),
+            }
+        }
+        for record in &self.file_tasks {
+            let path = record.path.clone();
+            let current = match FileState::read(&record.path).await {
+                Ok(state) => state,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    issues.push(VerifyIssue::Missing(path));
+                    continue;
+                }
+                Err(e) => {
+                    issues.push(VerifyIssue::Unreadable(path, e));
+                    continue;
+                }
+            };
+            let Some(expected) = &record.state else {
+                continue;
+            };
+            if current.size != expected.size || current.sha256 != expected.sha256 {
+                issues.push(VerifyIssue::Modified(path));
+            } else {
+                issues.extend(compare_attributes(
+                    path,
+                    &expected.attributes(),
+                    &current.attributes(),
+                ));
+            }
+        }
+        issues
     }
+}
 
-    pub fn from_binary(data: &[u8]) -> Self {
-        bincode::serde::decode_from_slice(data, bincode::config::standard())
-            .unwrap()
-            .0
+/// Reports the first of mode and ownership set in `expected` that differs.
+fn compare_attributes(
+    path: PathBuf,
+    expected: &Attributes,
+    actual: &Attributes,
+) -> Option<VerifyIssue> {
+    let differs =
+        |expected: Option<u32>, actual: Option<u32>| expected.is_some_and(|e| Some(e) != actual);
+    if differs(expected.mode, actual.mode) {
+        Some(VerifyIssue::PermissionsChanged {
+            path,
+            expected: expected.mode,
+            actual: actual.mode,
+        })
+    } else if differs(expected.uid, actual.uid) || differs(expected.gid, actual.gid) {
+        Some(VerifyIssue::OwnerChanged {
+            path,
+            expected: (expected.uid, expected.gid),
+            actual: (actual.uid, actual.gid),
+        })
+    } else {
+        None
     }
 }
 
-impl Default for Recorder {
-    fn default() -> Self {
-        Self {
-            dir_tasks: Vec::new(),
-            file_tasks: Vec::new(),
-            link_tasks: Vec::new(),
+#[derive(Debug)]
+pub enum VerifyIssue {
+    Missing(PathBuf),
+    Unreadable(PathBuf, io::Error),
+    /// The size or contents differ.
+    Modified(PathBuf),
+    PermissionsChanged {
+        path: PathBuf,
+        expected: Option<u32>,
+        actual: Option<u32>,
+    },
+    /// Owner and group ids, as expected and as found.
+    OwnerChanged {
+        path: PathBuf,
+        expected: (Option<u32>, Option<u32>),
+        actual: (Option<u32>, Option<u32>),
+    },
+}
+
+impl VerifyIssue {
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            VerifyIssue::Missing(path)
+            | VerifyIssue::Unreadable(path, _)
+            | VerifyIssue::Modified(path)
+            | VerifyIssue::PermissionsChanged { path, .. }
+            | VerifyIssue::OwnerChanged { path, .. } => path,
+        }
+    }
+}
+
+async fn remove_file(path: &Path) -> io::Result<()> {
+    match bundle_deploy::file_system::remove_file(path).await {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
+}
+
+#[derive(Debug)]
+pub enum RollbackFailure {
+    RemoveFile(PathBuf, io::Error),
+    RemoveLink(PathBuf, io::Error),
+    RemoveDirectory(PathBuf, io::Error),
+    RevertEnv(PathBuf, io::Error),
+    RestoreBackup(PathBuf, io::Error),
+}
+
+#[derive(Debug)]
+pub struct RollbackErr(pub Vec<RollbackFailure>);
+
+impl RollbackErr {
+    pub fn failures(&self) -> &[RollbackFailure] {
+        &self.0
+    }
+}
+
+impl Display for RollbackErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+impl std::error::Error for RollbackErr {}
+
+pub type RollbackResult = Result<(), RollbackErr>;
+
+/// Magic bytes at the start of every encoded recorder.
+const MAGIC: &[u8; 4] = b"VMRC";
+/// Current version of the binary format.
+///
+/// Version 0 is the bare bincode encoding used before the header existed,
+/// version 1 recorded files by path only, version 2 had no backups, and
+/// version 3 recorded neither ownership nor directory attributes, and version
+/// 4 did not record whether an environment file was created. Before the
+/// records change again, their current layout has to be frozen into a `v5`
+/// module like the others.
+const FORMAT_VERSION: u16 = 5;
+
+impl Recorder {
+    /// Encodes the recorder as a header (magic plus format version) followed
+    /// by its bincode encoding.
+    pub fn to_binary(&self) -> FormatResult<Vec<u8>> {
+        let mut data = Vec::with_capacity(256);
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bincode::serde::encode_into_std_write(self, &mut data, bincode::config::standard())
+            .map_err(FormatErr::Encode)?;
+        Ok(data)
+    }
+
+    /// Decodes a recorder written by any known format version, upgrading it
+    /// to the current one.
+    pub fn from_binary(data: &[u8]) -> FormatResult<Self> {
+        let (version, payload) = match data.strip_prefix(MAGIC) {
+            Some(rest) if rest.len() >= 2 => (u16::from_le_bytes([rest[0], rest[1]]), &rest[2..]),
+            Some(_) => return Err(FormatErr::Truncated),
+            None => (0, data),
+        };
+        match version {
+            0 => decode::<v0::Recorder>(payload).map(Self::from),
+            1 => decode::<v1::Recorder>(payload).map(Self::from),
+            2 => decode::<v2::Recorder>(payload).map(Self::from),
+            3 => decode::<v3::Recorder>(payload).map(Self::from),
+            4 => decode::<v4::Recorder>(payload).map(Self::from),
+            FORMAT_VERSION => decode::<Self>(payload),
+            _ => Err(FormatErr::UnknownVersion(version)),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+fn decode<T: serde::de::DeserializeOwned>(payload: &[u8]) -> FormatResult<T> {
+    bincode::serde::decode_from_slice(payload, bincode::config::standard())
+        .map(|(value, _)| value)
+        .map_err(FormatErr::Decode)
+}
+
+// Every module below holds frozen copies of the records as they were encoded
+// by its version. They must never refer to the live records, which keep
+// changing with the current format.
+
+/// Recorders as stored before the format header was introduced.
+mod v0 {
+    use serde::Deserialize;
+    use std::path::PathBuf;
+
+    /// Env tasks were never executed, so these records carried nothing.
+    #[derive(Deserialize)]
+    pub struct EnvRecord {}
+
+    #[derive(Deserialize)]
+    pub struct Recorder {
+        pub dir_tasks: Vec<PathBuf>,
+        pub file_tasks: Vec<PathBuf>,
+        pub link_tasks: Vec<PathBuf>,
+        #[allow(dead_code)]
+        pub env_tasks: Vec<EnvRecord>,
+    }
+}
+
+impl From<v0::Recorder> for Recorder {
+    fn from(old: v0::Recorder) -> Self {
+        Self::from(v1::Recorder {
+            dir_tasks: old.dir_tasks,
+            file_tasks: old.file_tasks,
+            link_tasks: old.link_tasks,
             env_tasks: Vec::new(),
+        })
+    }
+}
+
+/// Recorders from before file states were captured.
+mod v1 {
+    use serde::Deserialize;
+    use std::path::PathBuf;
+
+    #[derive(Deserialize)]
+    pub struct EnvRecord {
+        pub path: PathBuf,
+        pub line: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Recorder {
+        pub dir_tasks: Vec<PathBuf>,
+        pub file_tasks: Vec<PathBuf>,
+        pub link_tasks: Vec<PathBuf>,
+        pub env_tasks: Vec<EnvRecord>,
+    }
+}
+
+impl From<v1::Recorder> for Recorder {
+    fn from(old: v1::Recorder) -> Self {
+        Self::from(v2::Recorder {
+            dir_tasks: old.dir_tasks,
+            file_tasks: old
+                .file_tasks
+                .into_iter()
+                .map(|path| v2::FileRecord { path, state: None })
+                .collect(),
+            link_tasks: old.link_tasks.into_iter().map(v2::LinkRecord).collect(),
+            env_tasks: old
+                .env_tasks
+                .into_iter()
+                .map(|r| v2::EnvRecord {
+                    path: r.path,
+                    line: r.line,
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Recorders from before conflicting files were backed up.
+mod v2 {
+    use serde::Deserialize;
+    use std::path::PathBuf;
+
+    #[derive(Deserialize)]
+    pub struct FileState {
+        pub size: u64,
+        pub sha256: String,
+        pub mode: Option<u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FileRecord {
+        pub path: PathBuf,
+        pub state: Option<FileState>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LinkRecord(pub PathBuf);
+
+    #[derive(Deserialize)]
+    pub struct EnvRecord {
+        pub path: PathBuf,
+        pub line: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Recorder {
+        pub dir_tasks: Vec<PathBuf>,
+        pub file_tasks: Vec<FileRecord>,
+        pub link_tasks: Vec<LinkRecord>,
+        pub env_tasks: Vec<EnvRecord>,
+    }
+}
+
+impl From<v2::Recorder> for Recorder {
+    fn from(old: v2::Recorder) -> Self {
+        Self::from(v3::Recorder {
+            dir_tasks: old.dir_tasks,
+            file_tasks: old
+                .file_tasks
+                .into_iter()
+                .map(|r| v3::FileRecord {
+                    path: r.path,
+                    state: r.state.map(|s| v3::FileState {
+                        size: s.size,
+                        sha256: s.sha256,
+                        mode: s.mode,
+                    }),
+                })
+                .collect(),
+            link_tasks: old
+                .link_tasks
+                .into_iter()
+                .map(|r| v3::LinkRecord(r.0))
+                .collect(),
+            env_tasks: old
+                .env_tasks
+                .into_iter()
+                .map(|r| v3::EnvRecord {
+                    path: r.path,
+                    line: r.line,
+                })
+                .collect(),
+            backup_tasks: Vec::new(),
+        })
+    }
+}
+
+/// Recorders from before ownership and directory attributes were recorded.
+mod v3 {
+    use serde::Deserialize;
+    use std::path::PathBuf;
+
+    #[derive(Deserialize)]
+    pub struct FileState {
+        pub size: u64,
+        pub sha256: String,
+        pub mode: Option<u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FileRecord {
+        pub path: PathBuf,
+        pub state: Option<FileState>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LinkRecord(pub PathBuf);
+
+    #[derive(Deserialize)]
+    pub struct EnvRecord {
+        pub path: PathBuf,
+        pub line: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct BackupRecord {
+        pub original: PathBuf,
+        pub backup: PathBuf,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Recorder {
+        pub dir_tasks: Vec<PathBuf>,
+        pub file_tasks: Vec<FileRecord>,
+        pub link_tasks: Vec<LinkRecord>,
+        pub env_tasks: Vec<EnvRecord>,
+        pub backup_tasks: Vec<BackupRecord>,
+    }
+}
+
+impl From<v3::Recorder> for Recorder {
+    fn from(old: v3::Recorder) -> Self {
+        Self::from(v4::Recorder {
+            dir_tasks: old
+                .dir_tasks
+                .into_iter()
+                .map(|path| v4::DirectoryRecord {
+                    path,
+                    mode: None,
+                    uid: None,
+                    gid: None,
+                })
+                .collect(),
+            file_tasks: old
+                .file_tasks
+                .into_iter()
+                .map(|r| v4::FileRecord {
+                    path: r.path,
+                    state: r.state.map(|s| v4::FileState {
+                        size: s.size,
+                        sha256: s.sha256,
+                        mode: s.mode,
+                        uid: None,
+                        gid: None,
+                    }),
+                })
+                .collect(),
+            link_tasks: old
+                .link_tasks
+                .into_iter()
+                .map(|r| v4::LinkRecord(r.0))
+                .collect(),
+            env_tasks: old
+                .env_tasks
+                .into_iter()
+                .map(|r| v4::EnvRecord {
+                    path: r.path,
+                    line: r.line,
+                })
+                .collect(),
+            backup_tasks: old
+                .backup_tasks
+                .into_iter()
+                .map(|r| v4::BackupRecord {
+                    original: r.original,
+                    backup: r.backup,
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Recorders from before it was recorded whether an environment file was
+/// created.
+mod v4 {
+    use serde::Deserialize;
+    use std::path::PathBuf;
+
+    #[derive(Deserialize)]
+    pub struct DirectoryRecord {
+        pub path: PathBuf,
+        pub mode: Option<u32>,
+        pub uid: Option<u32>,
+        pub gid: Option<u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FileState {
+        pub size: u64,
+        pub sha256: String,
+        pub mode: Option<u32>,
+        pub uid: Option<u32>,
+        pub gid: Option<u32>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct FileRecord {
+        pub path: PathBuf,
+        pub state: Option<FileState>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LinkRecord(pub PathBuf);
+
+    #[derive(Deserialize)]
+    pub struct EnvRecord {
+        pub path: PathBuf,
+        pub line: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct BackupRecord {
+        pub original: PathBuf,
+        pub backup: PathBuf,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Recorder {
+        pub dir_tasks: Vec<DirectoryRecord>,
+        pub file_tasks: Vec<FileRecord>,
+        pub link_tasks: Vec<LinkRecord>,
+        pub env_tasks: Vec<EnvRecord>,
+        pub backup_tasks: Vec<BackupRecord>,
+    }
+}
+
+impl From<v4::Recorder> for Recorder {
+    fn from(old: v4::Recorder) -> Self {
+        Self {
+            dir_tasks: old
+                .dir_tasks
+                .into_iter()
+                .map(|r| DirectoryRecord {
+                    path: r.path,
+                    mode: r.mode,
+                    uid: r.uid,
+                    gid: r.gid,
+                })
+                .collect(),
+            file_tasks: old
+                .file_tasks
+                .into_iter()
+                .map(|r| FileRecord {
+                    path: r.path,
+                    state: r.state.map(|s| FileState {
+                        size: s.size,
+                        sha256: s.sha256,
+                        mode: s.mode,
+                        uid: s.uid,
+                        gid: s.gid,
+                    }),
+                })
+                .collect(),
+            link_tasks: old
+                .link_tasks
+                .into_iter()
+                .map(|r| LinkRecord(r.0))
+                .collect(),
+            // Whether the file was created is unknown, so it is assumed that
+            // it was not and the file is never removed.
+            env_tasks: old
+                .env_tasks
+                .into_iter()
+                .map(|r| EnvRecord::new(r.path, r.line, false))
+                .collect(),
+            backup_tasks: old
+                .backup_tasks
+                .into_iter()
+                .map(|r| BackupRecord::new(r.original, r.backup))
+                .collect(),
         }
     }
 }
+
+#[derive(Debug)]
+pub enum FormatErr {
+    Encode(bincode::error::EncodeError),
+    Decode(bincode::error::DecodeError),
+    Truncated,
+    UnknownVersion(u16),
+}
+
+impl Display for FormatErr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+impl std::error::Error for FormatErr {}
+
+pub type FormatResult<T> = Result<T, FormatErr>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bundle_deploy::env::EnvFileFormat;
+
+    #[test]
+    fn rollback_undoes_everything_recorded() {
+        let dir =
+            std::env::temp_dir().join(format!("veridian-recorder-rollback-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("kept")).unwrap();
+        std::fs::create_dir_all(dir.join("emptied")).unwrap();
+        std::fs::write(dir.join("kept/user-file"), "not ours").unwrap();
+        std::fs::write(dir.join("emptied/file"), "ours").unwrap();
+        std::fs::write(dir.join("replaced"), "ours").unwrap();
+        std::fs::write(dir.join("replaced.backup"), "original").unwrap();
+        std::fs::write(dir.join("env.sh"), "export A=1\n").unwrap();
+        let env_line = "export PATH=\"/opt/demo/bin:$PATH\"";
+        let target = bundle_deploy::env::EnvTarget::new(dir.join("env.sh"), EnvFileFormat::Shell);
+        let change = bundle_deploy::env::EnvChange::PrependPath("/opt/demo/bin".into());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let applied = runtime
+            .block_on(bundle_deploy::env::apply(&target, &change))
+            .unwrap();
+        assert_eq!(applied.line, env_line);
+
+        let mut recorder = Recorder::default();
+        recorder.record_directory(DirectoryRecord::from(dir.join("kept")));
+        recorder.record_directory(DirectoryRecord::from(dir.join("emptied")));
+        recorder.record_file(FileRecord::from(dir.join("emptied/file")));
+        recorder.record_file(FileRecord::from(dir.join("replaced")));
+        recorder.record_file(FileRecord::from(dir.join("already-gone")));
+        recorder.record_backup(BackupRecord::new(
+            dir.join("replaced"),
+            dir.join("replaced.backup"),
+        ));
+        recorder.record_env(EnvRecord::new(
+            dir.join("env.sh"),
+            applied.line,
+            applied.created,
+        ));
+        runtime.block_on(recorder.rollback()).unwrap();
+
+        assert!(dir.join("kept/user-file").exists());
+        assert!(!dir.join("emptied").exists());
+        assert_eq!(std::fs::read(dir.join("replaced")).unwrap(), b"original");
+        assert!(!dir.join("replaced.backup").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("env.sh")).unwrap(),
+            "export A=1\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn fixture(version: u16) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures/recorder")
+            .join(format!("v{}.bin", version));
+        std::fs::read(path).unwrap()
+    }
+
+    /// What every fixture holds once upgraded, given what its version could
+    /// record.
+    fn expected(version: u16) -> Recorder {
+        let mut recorder = Recorder::default();
+        let (dir_attributes, owner) = if version >= 4 {
+            let dir = Attributes {
+                mode: Some(0o755),
+                uid: Some(0),
+                gid: Some(0),
+            };
+            (dir, Some(1000))
+        } else {
+            (Attributes::default(), None)
+        };
+        recorder.record_directory(DirectoryRecord::new("/opt/demo".into(), &dir_attributes));
+        if version >= 2 {
+            let state = FileState {
+                size: 5,
+                sha256: "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+                    .to_string(),
+                mode: Some(0o644),
+                uid: owner,
+                gid: owner,
+            };
+            recorder.record_file(FileRecord::new("/opt/demo/a".into(), state));
+        } else {
+            recorder.record_file(FileRecord::from(PathBuf::from("/opt/demo/a")));
+        }
+        recorder.record_file(FileRecord::from(PathBuf::from("/opt/demo/b")));
+        recorder.record_link(LinkRecord::from(PathBuf::from("/usr/local/bin/demo")));
+        if version >= 1 {
+            recorder.record_env(EnvRecord::new(
+                "/home/u/.profile".into(),
+                "export PATH=\"/opt/demo/bin:$PATH\"".to_string(),
+                version >= 5,
+            ));
+        }
+        if version >= 3 {
+            recorder.record_backup(BackupRecord::new(
+                "/usr/local/bin/demo".into(),
+                "/usr/local/bin/demo.veridian-backup-1".into(),
+            ));
+        }
+        recorder
+    }
+
+    #[test]
+    fn every_format_version_is_upgraded() {
+        for version in 0..=FORMAT_VERSION {
+            let recorder = Recorder::from_binary(&fixture(version))
+                .unwrap_or_else(|e| panic!("v{} does not decode: {}", version, e));
+            assert_eq!(
+                recorder.to_json().unwrap(),
+                expected(version).to_json().unwrap(),
+                "v{} upgraded wrongly",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn current_format_round_trips() {
+        let data = fixture(FORMAT_VERSION);
+        let recorder = Recorder::from_binary(&data).unwrap();
+        assert_eq!(recorder.to_binary().unwrap(), data);
+        assert_eq!(expected(FORMAT_VERSION).to_binary().unwrap(), data);
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            Recorder::from_binary(&data),
+            Err(FormatErr::UnknownVersion(v)) if v == FORMAT_VERSION + 1
+        ));
+        assert!(matches!(
+            Recorder::from_binary(b"VMRC\x04"),
+            Err(FormatErr::Truncated)
+        ));
+    }
+}
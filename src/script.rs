@@ -0,0 +1,574 @@
+use crate::{config, installer, installer_builder};
+use bundle_deploy::env::EnvChange;
+use bundle_deploy::file_system::{Attributes, RelativePath};
+use rhai::{Dynamic, Engine, EvalAltResult, Position, Scope};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+/// Creates an engine with the whole installer API registered for installing
+/// into `profile`. Builders created by scripts write environment changes to
+/// the profile's env file, and files can only be given an owner when the
+/// profile requires root.
+pub fn create_engine(profile: &config::Profile) -> Engine {
+    let env_target = profile.env_target();
+    let non_root_profile = (!profile.requires_root).then(|| profile.name.clone());
+    let mut engine = Engine::new();
+    engine
+        .register_type_with_name::<installer_builder::InstallerBuilder>("InstallerBuilder")
+        .register_fn("InstallerBuilder", move || {
+            let mut builder = installer_builder::InstallerBuilder::new();
+            builder.set_env_target(env_target.clone());
+            builder
+        })
+        .register_fn(
+            "set_name",
+            |b: &mut installer_builder::InstallerBuilder, v: &str| {
+                b.metadata_mut().name = v.to_string();
+            },
+        )
+        .register_fn(
+            "set_version",
+            |b: &mut installer_builder::InstallerBuilder, v: &str| {
+                b.metadata_mut().version = Some(v.to_string());
+            },
+        )
+        .register_fn(
+            "set_description",
+            |b: &mut installer_builder::InstallerBuilder, v: &str| {
+                b.metadata_mut().description = Some(v.to_string());
+            },
+        )
+        .register_fn(
+            "set_publisher",
+            |b: &mut installer_builder::InstallerBuilder, v: &str| {
+                b.metadata_mut().publisher = Some(v.to_string());
+            },
+        )
+        .register_fn(
+            "set_homepage",
+            |b: &mut installer_builder::InstallerBuilder, v: &str| {
+                b.metadata_mut().homepage = Some(v.to_string());
+            },
+        )
+        .register_fn("set_conflict_policy", set_conflict_policy)
+        .register_fn("add_dir", add_dir)
+        .register_fn("add_source", add_source)
+        .register_fn("add_source", add_source_with_conflict)
+        .register_fn("add_file", add_file)
+        .register_fn("add_file", add_file_with_conflict)
+        .register_fn("add_link", add_symbolic_link)
+        .register_fn("add_link", add_link)
+        .register_fn("add_link", add_link_with_conflict)
+        .register_fn("set_mode", set_mode)
+        .register_fn(
+            "set_owner",
+            move |b: &mut installer_builder::InstallerBuilder,
+                  pattern: &str,
+                  user: &str,
+                  group: &str| {
+                if let Some(profile) = &non_root_profile {
+                    return Err(ScriptError::OwnerNeedsRoot(profile.clone()).into());
+                }
+                set_owner(b, pattern, user, group)
+            },
+        )
+        .register_fn("prepend_path", prepend_path)
+        .register_fn("set_env", set_env)
+        .register_fn("append_env", append_env);
+    engine
+        .register_type_with_name::<installer_builder::SourcePath>("SourcePath")
+        .register_fn("disk", disk)
+        .register_fn("archive", archive);
+    engine
+        .register_type_with_name::<config::Profile>("Profile")
+        .register_get("name", |profile: &mut config::Profile| profile.name.clone())
+        .register_get("install_path", |profile: &mut config::Profile| {
+            profile.default_install_path.to_string_lossy().into_owned()
+        })
+        .register_get("bin_dir", |profile: &mut config::Profile| {
+            profile.bin_dir.to_string_lossy().into_owned()
+        })
+        .register_get("desktop_dir", |profile: &mut config::Profile| {
+            profile.desktop_dir.to_string_lossy().into_owned()
+        })
+        .register_get("icon_dir", |profile: &mut config::Profile| {
+            profile.icon_dir.to_string_lossy().into_owned()
+        })
+        .register_get("requires_root", |profile: &mut config::Profile| {
+            profile.requires_root
+        });
+    engine
+}
+
+/// Runs the script at `path` with `profile` in scope and returns the builder it
+/// evaluates to. Builders created by the script write environment changes to
+/// the profile's env file.
+pub fn create_builder_from_script(
+    path: &Path,
+    profile: &config::Profile,
+) -> ScriptResult<installer_builder::InstallerBuilder> {
+    let engine = create_engine(profile);
+    let mut scope = Scope::new();
+    scope.push_constant("profile", profile.clone());
+    engine
+        .eval_file_with_scope::<installer_builder::InstallerBuilder>(&mut scope, path.to_path_buf())
+}
+
+fn disk(path: &str, pattern: &str) -> ScriptResult<installer_builder::SourcePath> {
+    let pattern =
+        glob::Pattern::new(pattern).map_err(|e| ScriptError::InvalidPattern(e.to_string()))?;
+    Ok(installer_builder::SourcePath::Disk(
+        PathBuf::from(path),
+        pattern,
+    ))
+}
+
+fn archive(path: &str, inner: &str, pattern: &str) -> ScriptResult<installer_builder::SourcePath> {
+    let inner = RelativePath::new(Path::new(inner).iter())
+        .map_err(|_| ScriptError::InvalidPath(inner.to_string()))?;
+    let pattern =
+        glob::Pattern::new(pattern).map_err(|e| ScriptError::InvalidPattern(e.to_string()))?;
+    Ok(installer_builder::SourcePath::Archive(
+        PathBuf::from(path),
+        inner,
+        pattern,
+    ))
+}
+
+fn conflict_policy(policy: &str) -> ScriptResult<installer::ConflictPolicy> {
+    policy
+        .parse()
+        .map_err(|_| ScriptError::UnknownConflictPolicy(policy.to_string()).into())
+}
+
+fn set_conflict_policy(
+    builder: &mut installer_builder::InstallerBuilder,
+    policy: &str,
+) -> ScriptResult<()> {
+    builder.set_conflict_policy(conflict_policy(policy)?);
+    Ok(())
+}
+
+fn add_dir(builder: &mut installer_builder::InstallerBuilder, path: &str) {
+    builder.add_dir(PathBuf::from(path));
+}
+
+fn add_source(
+    builder: &mut installer_builder::InstallerBuilder,
+    path: installer_builder::SourcePath,
+    destination: &str,
+) {
+    builder.add_source(installer_builder::Source {
+        path,
+        destination: PathBuf::from(destination),
+        conflict: None,
+    });
+}
+
+fn add_source_with_conflict(
+    builder: &mut installer_builder::InstallerBuilder,
+    path: installer_builder::SourcePath,
+    destination: &str,
+    conflict: &str,
+) -> ScriptResult<()> {
+    builder.add_source(installer_builder::Source {
+        path,
+        destination: PathBuf::from(destination),
+        conflict: Some(conflict_policy(conflict)?),
+    });
+    Ok(())
+}
+
+fn add_file(builder: &mut installer_builder::InstallerBuilder, to: &str, content: &str) {
+    builder.add_file(content.as_bytes().to_vec(), PathBuf::from(to), None);
+}
+
+fn add_file_with_conflict(
+    builder: &mut installer_builder::InstallerBuilder,
+    to: &str,
+    content: &str,
+    conflict: &str,
+) -> ScriptResult<()> {
+    let conflict = Some(conflict_policy(conflict)?);
+    builder.add_file(content.as_bytes().to_vec(), PathBuf::from(to), conflict);
+    Ok(())
+}
+
+fn add_symbolic_link(builder: &mut installer_builder::InstallerBuilder, from: &str, to: &str) {
+    builder.add_link(
+        PathBuf::from(from),
+        PathBuf::from(to),
+        installer::LinkType::Symbolic,
+        None,
+    );
+}
+
+fn link_type(link_type: &str) -> ScriptResult<installer::LinkType> {
+    match link_type {
+        "shortcut" => Ok(installer::LinkType::Shortcut),
+        "symbolic" => Ok(installer::LinkType::Symbolic),
+        "hard" => Ok(installer::LinkType::Hard),
+        _ => Err(ScriptError::UnknownLinkType(link_type.to_string()).into()),
+    }
+}
+
+fn add_link(
+    builder: &mut installer_builder::InstallerBuilder,
+    from: &str,
+    to: &str,
+    link_type_name: &str,
+) -> ScriptResult<()> {
+    let link_type = link_type(link_type_name)?;
+    builder.add_link(PathBuf::from(from), PathBuf::from(to), link_type, None);
+    Ok(())
+}
+
+fn add_link_with_conflict(
+    builder: &mut installer_builder::InstallerBuilder,
+    from: &str,
+    to: &str,
+    link_type_name: &str,
+    conflict: &str,
+) -> ScriptResult<()> {
+    let link_type = link_type(link_type_name)?;
+    let conflict = Some(conflict_policy(conflict)?);
+    builder.add_link(PathBuf::from(from), PathBuf::from(to), link_type, conflict);
+    Ok(())
+}
+
+fn set_mode(
+    builder: &mut installer_builder::InstallerBuilder,
+    pattern: &str,
+    mode: i64,
+) -> ScriptResult<()> {
+    let pattern =
+        glob::Pattern::new(pattern).map_err(|e| ScriptError::InvalidPattern(e.to_string()))?;
+    let mode = u32::try_from(mode)
+        .ok()
+        .filter(|m| *m <= 0o7777)
+        .ok_or(ScriptError::InvalidMode(mode))?;
+    let attributes = Attributes {
+        mode: Some(mode),
+        ..Attributes::default()
+    };
+    builder.set_attributes(pattern, attributes);
+    Ok(())
+}
+
+/// `user` and `group` are names or numeric ids. An empty string leaves that
+/// one unchanged.
+fn set_owner(
+    builder: &mut installer_builder::InstallerBuilder,
+    pattern: &str,
+    user: &str,
+    group: &str,
+) -> ScriptResult<()> {
+    let pattern =
+        glob::Pattern::new(pattern).map_err(|e| ScriptError::InvalidPattern(e.to_string()))?;
+    let lookup = |name: &str, f: fn(&str) -> std::io::Result<u32>| {
+        if name.is_empty() {
+            return Ok(None);
+        }
+        f(name)
+            .map(Some)
+            .map_err(|_| ScriptError::UnknownOwner(name.to_string()))
+    };
+    let attributes = Attributes {
+        uid: lookup(user, bundle_deploy::file_system::user_id)?,
+        gid: lookup(group, bundle_deploy::file_system::group_id)?,
+        ..Attributes::default()
+    };
+    builder.set_attributes(pattern, attributes);
+    Ok(())
+}
+
+fn prepend_path(builder: &mut installer_builder::InstallerBuilder, path: &str) -> ScriptResult<()> {
+    add_env(builder, EnvChange::PrependPath(PathBuf::from(path)))
+}
+
+fn set_env(
+    builder: &mut installer_builder::InstallerBuilder,
+    name: &str,
+    value: &str,
+) -> ScriptResult<()> {
+    let name = name.to_string();
+    let value = value.to_string();
+    add_env(builder, EnvChange::Set { name, value })
+}
+
+fn append_env(
+    builder: &mut installer_builder::InstallerBuilder,
+    name: &str,
+    value: &str,
+) -> ScriptResult<()> {
+    let name = name.to_string();
+    let value = value.to_string();
+    add_env(builder, EnvChange::Append { name, value })
+}
+
+fn add_env(
+    builder: &mut installer_builder::InstallerBuilder,
+    change: EnvChange,
+) -> ScriptResult<()> {
+    let target = builder.env_target().clone();
+    if let Err(e) = change.render(&target.format) {
+        return Err(ScriptError::InvalidEnv(e.to_string()).into());
+    }
+    builder.add_env(target, change);
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub enum ScriptError {
+    InvalidPattern(String),
+    InvalidPath(String),
+    UnknownLinkType(String),
+    UnknownConflictPolicy(String),
+    InvalidMode(i64),
+    UnknownOwner(String),
+    /// `set_owner` in a profile, named here, that does not require root and
+    /// so cannot change ownership.
+    OwnerNeedsRoot(String),
+    InvalidEnv(String),
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:?}", self))
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<ScriptError> for Box<EvalAltResult> {
+    fn from(error: ScriptError) -> Self {
+        Box::new(EvalAltResult::ErrorRuntime(
+            Dynamic::from(error.to_string()),
+            Position::NONE,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(requires_root: bool) -> config::Profile {
+        config::Profile {
+            name: "test".to_string(),
+            default_install_path: PathBuf::from("/srv/install"),
+            bin_dir: PathBuf::from("/srv/bin"),
+            desktop_dir: PathBuf::from("/srv/applications"),
+            icon_dir: PathBuf::from("/srv/icons"),
+            env_file: PathBuf::from("/srv/env.sh"),
+            env_format: config::EnvFormat::Shell,
+            requires_root,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("veridian-script-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn eval<T: Clone + 'static>(profile: &config::Profile, script: &str) -> ScriptResult<T> {
+        let engine = create_engine(profile);
+        let mut scope = Scope::new();
+        scope.push_constant("profile", profile.clone());
+        engine.eval_with_scope::<T>(&mut scope, script)
+    }
+
+    #[test]
+    fn scripts_build_installers() {
+        let dir = temp_dir("build");
+        std::fs::create_dir_all(dir.join("source")).unwrap();
+        std::fs::write(dir.join("source/readme.txt"), "from disk").unwrap();
+        std::fs::write(dir.join("source/skipped.md"), "skipped").unwrap();
+        let profile = config::Profile {
+            default_install_path: dir.join("install"),
+            bin_dir: dir.join("bin"),
+            env_file: dir.join("env.sh"),
+            ..profile(false)
+        };
+        let script = format!(
+            r#"
+            let b = InstallerBuilder();
+            b.set_name("demo");
+            b.set_version("1.0");
+            let root = profile.install_path + "/demo";
+            b.add_dir(root);
+            b.add_source(disk("{}", "*.txt"), root);
+            b.add_file(root + "/written", "from memory");
+            b.add_link(root + "/written", root + "/link");
+            b.add_link(root + "/written", root + "/hard", "hard");
+            b.add_link(root + "/written", profile.bin_dir + "/demo");
+            b.prepend_path(root);
+            b
+            "#,
+            dir.join("source").display()
+        );
+        let builder: installer_builder::InstallerBuilder = eval(&profile, &script).unwrap();
+        assert_eq!(builder.metadata().name, "demo");
+        assert_eq!(builder.metadata().version.as_deref(), Some("1.0"));
+        let installer = builder.build().unwrap();
+        let application = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(installer.install())
+            .map_err(|(_, e)| e)
+            .unwrap();
+        assert_eq!(application.metadata().name, "demo");
+
+        let root = dir.join("install/demo");
+        assert_eq!(
+            std::fs::read(root.join("readme.txt")).unwrap(),
+            b"from disk"
+        );
+        assert!(!root.join("skipped.md").exists());
+        assert_eq!(std::fs::read(root.join("written")).unwrap(), b"from memory");
+        assert!(std::fs::symlink_metadata(root.join("link")).is_ok());
+        assert_eq!(std::fs::read(root.join("hard")).unwrap(), b"from memory");
+        assert_eq!(std::fs::read(dir.join("bin/demo")).unwrap(), b"from memory");
+        let env = std::fs::read_to_string(dir.join("env.sh")).unwrap();
+        assert!(env.contains(&root.display().to_string()), "{}", env);
+
+        let err = eval::<installer_builder::InstallerBuilder>(
+            &profile,
+            r#"let b = InstallerBuilder(); b.add_link("/a", "/b", "junction"); b"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("UnknownLinkType"), "{}", err);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sources_are_checked_when_created() {
+        let profile = profile(false);
+        let source: installer_builder::SourcePath =
+            eval(&profile, r#"disk("/src", "*.txt")"#).unwrap();
+        assert!(matches!(
+            source,
+            installer_builder::SourcePath::Disk(ref path, ref pattern)
+                if path == Path::new("/src") && pattern.as_str() == "*.txt"
+        ));
+        let source: installer_builder::SourcePath =
+            eval(&profile, r#"archive("/src/demo.zip", "demo/bin", "*")"#).unwrap();
+        assert!(matches!(
+            source,
+            installer_builder::SourcePath::Archive(ref path, _, _)
+                if path == Path::new("/src/demo.zip")
+        ));
+
+        let err =
+            eval::<installer_builder::SourcePath>(&profile, r#"disk("/src", "[")"#).unwrap_err();
+        assert!(err.to_string().contains("InvalidPattern"), "{}", err);
+        let err = eval::<installer_builder::SourcePath>(
+            &profile,
+            r#"archive("/src/demo.zip", "demo\\bin", "*")"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("InvalidPath"), "{}", err);
+    }
+
+    #[test]
+    fn conflict_policies_are_set_from_scripts() {
+        let profile = profile(false);
+        let builder: installer_builder::InstallerBuilder = eval(
+            &profile,
+            r#"
+            let b = InstallerBuilder();
+            b.set_conflict_policy("backup");
+            b.add_file("/srv/install/demo/readme", "hello", "fail");
+            b.add_link("/srv/install/demo/run", "/srv/bin/demo", "symbolic", "skip");
+            b
+            "#,
+        )
+        .unwrap();
+        let installer = builder.build().unwrap();
+        assert_eq!(
+            installer.conflict_policy(),
+            installer::ConflictPolicy::Backup
+        );
+        let [file] = installer.file_tasks() else {
+            panic!("expected one file task");
+        };
+        assert_eq!(file.conflict(), Some(installer::ConflictPolicy::Fail));
+        let [link] = installer.link_tasks() else {
+            panic!("expected one link task");
+        };
+        assert_eq!(link.conflict(), Some(installer::ConflictPolicy::Skip));
+
+        let err = eval::<installer_builder::InstallerBuilder>(
+            &profile,
+            r#"let b = InstallerBuilder(); b.set_conflict_policy("merge"); b"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("UnknownConflictPolicy"), "{}", err);
+    }
+
+    #[test]
+    fn file_modes_and_owners_are_set_from_scripts() {
+        let profile = profile(true);
+        let builder: installer_builder::InstallerBuilder = eval(
+            &profile,
+            r#"
+            let b = InstallerBuilder();
+            b.add_dir("/srv/install/demo");
+            b.add_file("/srv/install/demo/run", "exec demo");
+            b.set_mode("/srv/install/demo/run", 0o750);
+            b.set_owner("/srv/install/demo/**", "0", "");
+            b
+            "#,
+        )
+        .unwrap();
+        let installer = builder.build().unwrap();
+        let [file] = installer.file_tasks() else {
+            panic!("expected one file task");
+        };
+        assert_eq!(file.attributes().mode, Some(0o750));
+        assert_eq!(file.attributes().uid, Some(0));
+        assert_eq!(file.attributes().gid, None);
+
+        let err = eval::<installer_builder::InstallerBuilder>(
+            &profile,
+            r#"let b = InstallerBuilder(); b.set_mode("/srv/**", 0o17777); b"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("InvalidMode"), "{}", err);
+        let err = eval::<installer_builder::InstallerBuilder>(
+            &profile,
+            r#"let b = InstallerBuilder(); b.set_owner("/srv/**", "no-such-user-here", ""); b"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("UnknownOwner"), "{}", err);
+    }
+
+    #[test]
+    fn profile_is_readable_from_scripts() {
+        let profile = profile(true);
+        let values: String = eval(
+            &profile,
+            r#"`${profile.name}|${profile.install_path}|${profile.bin_dir}|${profile.desktop_dir}|${profile.icon_dir}|${profile.requires_root}`"#,
+        )
+        .unwrap();
+        assert_eq!(
+            values,
+            "test|/srv/install|/srv/bin|/srv/applications|/srv/icons|true"
+        );
+    }
+
+    #[test]
+    fn set_owner_needs_a_profile_that_requires_root() {
+        let script = r#"let b = InstallerBuilder(); b.set_owner("/srv/**", "0", "0"); b"#;
+        let err = eval::<installer_builder::InstallerBuilder>(&profile(false), script).unwrap_err();
+        assert!(err.to_string().contains("OwnerNeedsRoot"), "{}", err);
+
+        let builder: installer_builder::InstallerBuilder = eval(&profile(true), script).unwrap();
+        let installer = builder.build().unwrap();
+        assert!(installer.file_tasks().is_empty());
+    }
+}